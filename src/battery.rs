@@ -0,0 +1,131 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Battery-backed save data stored as a .sav file next to the ROM
+pub struct BatteryFile {
+    path: PathBuf,
+    saved: Vec<u8>,
+    last_flush: Instant,
+}
+
+pub fn sav_path(rom_path: &Path) -> PathBuf {
+    rom_path.with_extension("sav")
+}
+
+impl BatteryFile {
+    pub fn new(path: PathBuf) -> Self {
+        BatteryFile {
+            path,
+            saved: vec![],
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn for_rom(rom_path: &Path) -> Self {
+        Self::new(sav_path(rom_path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Returns None when there is no save yet
+    pub fn load(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                self.saved = data.clone();
+                Ok(Some(data))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Writes the data out if it changed since the last save. The file is
+    // replaced atomically so a crash mid-write can't corrupt the old save.
+    pub fn flush(&mut self, data: &[u8]) -> io::Result<bool> {
+        self.last_flush = Instant::now();
+        if data == self.saved.as_slice() {
+            return Ok(false);
+        }
+
+        let tmp_path = self.path.with_extension("sav.tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.saved = data.to_vec();
+
+        Ok(true)
+    }
+
+    pub fn flush_if_due(&mut self, data: &[u8]) -> io::Result<bool> {
+        if self.last_flush.elapsed() < FLUSH_INTERVAL {
+            return Ok(false);
+        }
+
+        self.flush(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("kiko-nes-{}-{}.sav", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_sav_path_replaces_extension() {
+        assert_eq!(
+            sav_path(Path::new("games/zelda.nes")),
+            Path::new("games/zelda.sav")
+        );
+    }
+
+    #[test]
+    fn test_missing_save_loads_as_none() {
+        let mut battery = BatteryFile::new(temp_path("missing"));
+        assert!(battery.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unreadable_save_is_an_error() {
+        let mut battery = BatteryFile::new(std::env::temp_dir());
+        assert!(battery.load().is_err());
+    }
+
+    #[test]
+    fn test_flush_only_writes_changes() {
+        let path = temp_path("flush");
+        let mut battery = BatteryFile::new(path.clone());
+        assert!(battery.flush(&[1, 2, 3]).unwrap());
+        assert!(!battery.flush(&[1, 2, 3]).unwrap());
+        assert!(battery.flush(&[4, 5, 6]).unwrap());
+        assert!(!path.with_extension("sav.tmp").exists());
+
+        let mut reloaded = BatteryFile::new(path.clone());
+        assert_eq!(reloaded.load().unwrap(), Some(vec![4, 5, 6]));
+        // Loading counts as saved, so flushing the same data is a no-op
+        assert!(!reloaded.flush(&[4, 5, 6]).unwrap());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_flush_if_due_waits_for_interval() {
+        let path = temp_path("due");
+        let mut battery = BatteryFile::new(path.clone());
+        assert!(!battery.flush_if_due(&[1]).unwrap());
+        assert!(!path.exists());
+
+        battery.last_flush -= FLUSH_INTERVAL;
+        assert!(battery.flush_if_due(&[1]).unwrap());
+        assert!(path.exists());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,68 +1,412 @@
-use crate::{cartridge::ROM, cpu::Mem};
+use crate::{
+    cpu::Mem,
+    joypad::Joypad,
+    mapper::Mapper,
+    savestate::{StateReader, StateWriter},
+    watchpoint::{AddressSpace, WatchKind, Watchpoints},
+};
 
 const RAM: u16 = 0;
 const RAM_MIRRORS_END: u16 = 0x1fff;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3fff;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD_1: u16 = 0x4016;
+const JOYPAD_2: u16 = 0x4017;
+const TEST_REGISTERS: u16 = 0x4018;
+const TEST_REGISTERS_END: u16 = 0x401f;
+const CARTRIDGE: u16 = 0x4020;
+const CARTRIDGE_END: u16 = 0xffff;
+
+const PATTERN_TABLES: u16 = 0x0000;
+const PATTERN_TABLES_END: u16 = 0x1fff;
+const NAMETABLES: u16 = 0x2000;
+const NAMETABLES_MIRRORS_END: u16 = 0x3eff;
+const PALETTE: u16 = 0x3f00;
+const PALETTE_MIRRORS_END: u16 = 0x3fff;
 
 pub struct Bus {
     vram: [u8; 2048],
-    rom: ROM,
+    // Nametable RAM. Only four-screen boards use more than the console's 2K.
+    ciram: [u8; 4096],
+    palette_table: [u8; 32],
+    mapper: Box<dyn Mapper>,
+    joypads: [Joypad; 2],
+    // Last value driven on the data bus, returned by reads nothing answers
+    open_bus: u8,
+    // CPU cycles since power on
+    cycles: u64,
+    pub watchpoints: Watchpoints,
 }
 
 impl Bus {
-    pub fn new(rom: ROM) -> Self {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
         Self {
             vram: [0; 2048],
-            rom,
+            ciram: [0; 4096],
+            palette_table: [0; 32],
+            mapper,
+            joypads: [Joypad::new(), Joypad::new()],
+            open_bus: 0,
+            cycles: 0,
+            watchpoints: Watchpoints::new(),
+        }
+    }
+
+    // Port 0 is read through $4016, port 1 through $4017
+    pub fn joypad_mut(&mut self, port: usize) -> &mut Joypad {
+        &mut self.joypads[port]
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending()
+    }
+
+    // Called after each instruction with the CPU cycles it took
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.mapper.cpu_clock(cycles);
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn notify_scanline(&mut self) {
+        self.mapper.notify_scanline();
+    }
+
+    pub fn battery_data(&self) -> Option<&[u8]> {
+        self.mapper.battery_data()
+    }
+
+    pub fn load_battery_data(&mut self, data: &[u8]) {
+        self.mapper.load_battery_data(data);
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.ciram);
+        state.write_bytes(&self.palette_table);
+        state.write_u8(self.open_bus);
+        self.mapper.save_state(&mut state);
+        state.finish()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut state = StateReader::new(data);
+        state.read_bytes_into(&mut self.vram)?;
+        state.read_bytes_into(&mut self.ciram)?;
+        state.read_bytes_into(&mut self.palette_table)?;
+        self.open_bus = state.read_u8()?;
+        self.mapper.load_state(&mut state)
+    }
+
+    // Horizontal:
+    //   [ A ] [ a ]
+    //   [ B ] [ b ]
+    // Vertical:
+    //   [ A ] [ B ]
+    //   [ a ] [ b ]
+    fn mirror_nametable_addr(&self, addr: u16) -> usize {
+        let vram_index = (addr & 0x0fff) as usize;
+        let page = self.mapper.mirroring().ciram_page(vram_index / 0x400);
+        (page * 0x400) | (vram_index & 0x3ff)
+    }
+
+    fn nametable_read(&self, addr: u16) -> u8 {
+        self.mapper
+            .nametable_read(addr)
+            .unwrap_or_else(|| self.ciram[self.mirror_nametable_addr(addr)])
+    }
+
+    fn mirror_palette_addr(addr: u16) -> usize {
+        let index = (addr & 0x1f) as usize;
+        match index {
+            0x10 | 0x14 | 0x18 | 0x1c => index - 0x10,
+            _ => index,
+        }
+    }
+
+    pub fn ppu_peek(&self, addr: u16) -> Option<u8> {
+        let addr = addr & 0x3fff;
+        match addr {
+            NAMETABLES..=NAMETABLES_MIRRORS_END => Some(self.nametable_read(addr)),
+            PALETTE..=PALETTE_MIRRORS_END => {
+                Some(self.palette_table[Self::mirror_palette_addr(addr)])
+            }
+            // Pattern fetches can clock mapper latches
+            _ => None,
         }
     }
 
-    fn read_prg_rom(&self, addr: u16) -> u8 {
-        let mut addr = addr - 0x8000;
-        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    // PPU address space, $0000-$3FFF
+    pub fn ppu_read(&mut self, addr: u16) -> u8 {
+        let addr = addr & 0x3fff;
+        self.mapper.notify_ppu_addr(addr);
+
+        let data = match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => self.mapper.ppu_read(addr),
+            NAMETABLES..=NAMETABLES_MIRRORS_END => self.nametable_read(addr),
+            PALETTE..=PALETTE_MIRRORS_END => self.palette_table[Self::mirror_palette_addr(addr)],
+            _ => unreachable!(),
+        };
+
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .check(AddressSpace::Ppu, WatchKind::READ, addr, data, None);
         }
 
-        self.rom.prg_rom[addr as usize]
+        data
     }
-}
 
-impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    pub fn ppu_write(&mut self, addr: u16, data: u8) {
+        let addr = addr & 0x3fff;
+        self.mapper.notify_ppu_addr(addr);
+
+        if self
+            .watchpoints
+            .watches(AddressSpace::Ppu, WatchKind::WRITE)
+        {
+            let old_value = self.ppu_peek(addr);
+            self.watchpoints
+                .check(AddressSpace::Ppu, WatchKind::WRITE, addr, data, old_value);
+        }
+
+        match addr {
+            PATTERN_TABLES..=PATTERN_TABLES_END => self.mapper.ppu_write(addr, data),
+            NAMETABLES..=NAMETABLES_MIRRORS_END => {
+                if !self.mapper.nametable_write(addr, data) {
+                    let index = self.mirror_nametable_addr(addr);
+                    self.ciram[index] = data;
+                }
+            }
+            PALETTE..=PALETTE_MIRRORS_END => {
+                self.palette_table[Self::mirror_palette_addr(addr)] = data;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Reads memory without side effects, for debuggers. Returns None for
+    // registers whose reads would change state.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            RAM..=RAM_MIRRORS_END => Some(self.vram[(addr & 0b0000_0111_1111_1111) as usize]),
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.cpu_peek(addr),
+            _ => None,
+        }
+    }
+
+    // Called by the CPU before each instruction so watchpoint hits can report
+    // where they came from
+    pub fn begin_instruction(&mut self, pc: u16) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        self.watchpoints.set_pc(pc);
+        if self
+            .watchpoints
+            .watches(AddressSpace::Cpu, WatchKind::EXECUTE)
+        {
+            let opcode = self.peek(pc).unwrap_or(0);
+            self.watchpoints
+                .check(AddressSpace::Cpu, WatchKind::EXECUTE, pc, opcode, None);
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.vram[mirror_down_addr as usize]
             }
-            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                todo!("PPU is not supported")
-            }
-            0x8000..=0xffff => self.read_prg_rom(addr),
-            _ => {
-                println!("Ignoring mem access at {}", addr);
-                0
-            }
+            // There is no PPU yet, so its registers read back as open bus
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => self.open_bus,
+            // Controllers only drive the low bits
+            JOYPAD_1 => (self.open_bus & 0b1110_0000) | self.joypads[0].read(),
+            JOYPAD_2 => (self.open_bus & 0b1110_0000) | self.joypads[1].read(),
+            // Nothing answers these without an APU, and the CPU test
+            // registers are disabled on retail consoles
+            APU_REGISTERS..=APU_STATUS | TEST_REGISTERS..=TEST_REGISTERS_END => self.open_bus,
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.cpu_read(addr).unwrap_or(self.open_bus),
         }
     }
 
-    fn mem_write(&mut self, addr: u16, data: u8) {
+    fn write(&mut self, addr: u16, data: u8) {
         match addr {
             RAM..=RAM_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b1111_1111_1111_1111;
+                let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.vram[mirror_down_addr as usize] = data;
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                todo!("PPU is not supported");
+                let mirror_down_addr = addr & 0b0010_0000_0000_0111;
+                // The write itself is dropped until there is a PPU, but
+                // mappers like MMC5 still watch it
+                self.mapper
+                    .notify_ppu_register_write(mirror_down_addr, data);
             }
-            0x8000..=0xFFFF => {
-                panic!("Attempt to write to cartridge ROM space")
+            // One strobe line is shared by both ports
+            JOYPAD_1 => {
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write(data);
+                }
             }
-            _ => {
-                println!("Ignoring mem write-access at {}", addr);
+            // There is no APU yet. Sound writes, including the frame counter
+            // at $4017, are dropped so music code can still run.
+            // OAM DMA has no sprite memory to copy into either.
+            APU_REGISTERS..=APU_STATUS | JOYPAD_2 | TEST_REGISTERS..=TEST_REGISTERS_END => {}
+            CARTRIDGE..=CARTRIDGE_END => self.mapper.cpu_write(addr, data),
+        }
+    }
+}
+
+impl Mem for Bus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        let data = self.read(addr);
+        self.open_bus = data;
+
+        if !self.watchpoints.is_empty() {
+            self.watchpoints
+                .check(AddressSpace::Cpu, WatchKind::READ, addr, data, None);
+        }
+
+        data
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        if self
+            .watchpoints
+            .watches(AddressSpace::Cpu, WatchKind::WRITE)
+        {
+            let old_value = self.peek(addr);
+            self.watchpoints
+                .check(AddressSpace::Cpu, WatchKind::WRITE, addr, data, old_value);
+        }
+
+        self.open_bus = data;
+        self.write(addr, data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    // Takes a new mirroring from every write to $4020, and with
+    // `rom_nametable` set answers $2C00-$2FFF itself like boards that can map
+    // CHR-ROM there
+    struct SwitchingMapper {
+        mirroring: Mirroring,
+        rom_nametable: bool,
+    }
+
+    impl Mapper for SwitchingMapper {
+        fn cpu_read(&mut self, _addr: u16) -> Option<u8> {
+            None
+        }
+
+        fn cpu_peek(&self, _addr: u16) -> Option<u8> {
+            None
+        }
+
+        fn cpu_write(&mut self, _addr: u16, data: u8) {
+            self.mirroring = match data {
+                0 => Mirroring::Horizontal,
+                1 => Mirroring::Vertical,
+                2 => Mirroring::SingleScreenLower,
+                3 => Mirroring::SingleScreenUpper,
+                4 => Mirroring::FourScreen,
+                _ => Mirroring::Custom([3, 2, 1, 0]),
+            };
+            self.rom_nametable = data == 0xff;
+        }
+
+        fn ppu_read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+        fn mirroring(&self) -> Mirroring {
+            self.mirroring
+        }
+
+        fn nametable_read(&self, addr: u16) -> Option<u8> {
+            if self.rom_nametable && addr & 0x0c00 == 0x0c00 {
+                Some(0xcc)
+            } else {
+                None
             }
         }
+
+        fn nametable_write(&mut self, addr: u16, _data: u8) -> bool {
+            self.rom_nametable && addr & 0x0c00 == 0x0c00
+        }
+
+        fn save_state(&self, _state: &mut StateWriter) {}
+
+        fn load_state(&mut self, _state: &mut StateReader) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mirroring_follows_mapper_on_every_access() {
+        let mut bus = Bus::new(Box::new(SwitchingMapper {
+            mirroring: Mirroring::FourScreen,
+            rom_nametable: false,
+        }));
+        // Tag each CIRAM page through four-screen mode
+        for page in 0..4 {
+            bus.ppu_write(0x2000 + page * 0x400, page as u8);
+        }
+        let pages = |bus: &mut Bus| -> Vec<u8> {
+            (0..4).map(|nt| bus.ppu_read(0x2000 + nt * 0x400)).collect()
+        };
+
+        bus.mem_write(0x4020, 0);
+        assert_eq!(pages(&mut bus), vec![0, 0, 1, 1]);
+        bus.mem_write(0x4020, 1);
+        assert_eq!(pages(&mut bus), vec![0, 1, 0, 1]);
+        bus.mem_write(0x4020, 2);
+        assert_eq!(pages(&mut bus), vec![0, 0, 0, 0]);
+        bus.mem_write(0x4020, 3);
+        assert_eq!(pages(&mut bus), vec![1, 1, 1, 1]);
+        bus.mem_write(0x4020, 5);
+        assert_eq!(pages(&mut bus), vec![3, 2, 1, 0]);
+
+        // $3000-$3EFF mirrors the nametables
+        assert_eq!(bus.ppu_read(0x3000), 3);
+
+        bus.mem_write(0x4020, 0xff);
+        bus.ppu_write(0x2c00, 0x55);
+        assert_eq!(pages(&mut bus), vec![3, 2, 1, 0xcc]);
+    }
+
+    #[test]
+    fn test_unanswered_io_reads_open_bus() {
+        let mut bus = Bus::new(Box::new(SwitchingMapper {
+            mirroring: Mirroring::Horizontal,
+            rom_nametable: false,
+        }));
+        bus.mem_write(0x0000, 0x5a);
+        for addr in [0x4000, 0x4014, 0x4015, 0x4018, 0x401f] {
+            bus.mem_write(addr, 0xff);
+            bus.mem_read(0x0000);
+            assert_eq!(bus.mem_read(addr), 0x5a);
+        }
     }
 }
@@ -1,55 +1,598 @@
+use std::fmt;
+
+use crate::game_db::{Correction, GameDb};
+use crate::hash::{Crc32, Sha1};
+use crate::unif;
+
 const NES_TAG: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
+const DISKDUDE_TAG: &[u8] = b"DiskDude!";
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const INST_ROM_SIZE: usize = 0x2000;
+const PLAYCHOICE_PROM_SIZE: usize = 32;
+// Far beyond any real board, only here to reject nonsense sizes
+const MAX_ROM_SIZE: usize = 0x400_0000;
 const PRG_ROM_PAGE_SIZE: usize = 0x4000;
 const CHR_ROM_PAGE_SIZE: usize = 0x2000;
+const PRG_RAM_PAGE_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x2000;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    // All four nametables show the same 1K of CIRAM
+    SingleScreenLower,
+    SingleScreenUpper,
+    // CIRAM page shown by each of the four nametables. Pages 2 and 3 are
+    // the extra RAM of four-screen boards.
+    Custom([u8; 4]),
+}
+
+impl Mirroring {
+    // 1K CIRAM page behind nametable 0-3 ($2000, $2400, $2800, $2C00).
+    // Mappers can return a different arrangement from every call to
+    // Mapper::mirroring, so this is worked out on each access.
+    pub fn ciram_page(&self, nametable: usize) -> usize {
+        let nametable = nametable & 0b11;
+        match self {
+            Mirroring::Vertical => nametable & 1,
+            Mirroring::Horizontal => nametable >> 1,
+            Mirroring::FourScreen => nametable,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::Custom(pages) => (pages[nametable] & 0b11) as usize,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderFormat {
+    INes,
+    Nes2,
+    Unif,
+}
+
+// CPU/PPU timing the game was made for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    // NES 2.0 extended console type from byte 13
+    Extended(u8),
+}
+
+// Problems found in the file that ROM::new worked around
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderWarning {
+    DiskDudeSignature,
+    GarbageInUnusedBytes,
+    MissingTrainer,
+    TruncatedChrRom { expected: usize, found: usize },
+    MissingInstRom,
+    TrailingData { len: usize },
+}
+
+impl fmt::Display for HeaderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderWarning::DiskDudeSignature => {
+                write!(f, "\"DiskDude!\" found in header, ignoring bytes 7-15")
+            }
+            HeaderWarning::GarbageInUnusedBytes => {
+                write!(f, "Unused header bytes are not zero, ignoring bytes 7-15")
+            }
+            HeaderWarning::MissingTrainer => {
+                write!(f, "Trainer flag is set but the file has no trainer")
+            }
+            HeaderWarning::TruncatedChrRom { expected, found } => write!(
+                f,
+                "CHR-ROM is truncated: expected {} bytes, found {}",
+                expected, found
+            ),
+            HeaderWarning::MissingInstRom => {
+                write!(f, "PlayChoice-10 INST-ROM is missing")
+            }
+            HeaderWarning::TrailingData { len } => {
+                write!(f, "{} bytes of unknown data after CHR-ROM", len)
+            }
+        }
+    }
 }
 
 pub struct ROM {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub header_format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
     pub mirroring: Mirroring,
+    pub battery: bool,
+    // Volatile and battery-backed RAM are declared separately by NES 2.0.
+    // iNES headers only have one size, which counts as NVRAM if the battery
+    // flag is set.
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_rom_count: u8,
+    pub expansion_device: u8,
+    // PlayChoice-10 instruction ROM and its decryption PROM, when present
+    pub inst_rom: Vec<u8>,
+    pub playchoice_prom: Vec<u8>,
+    // NES 2.0 miscellaneous ROM area following CHR-ROM
+    pub misc_rom: Vec<u8>,
+    // 512 bytes the game expects at $7000-$71FF, empty when there is none
+    pub trainer: Vec<u8>,
+    pub warnings: Vec<HeaderWarning>,
+    // Checksums of PRG-ROM followed by CHR-ROM, used to look the dump up in
+    // the game database
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+    // Set when the game database knows this dump
+    pub board: Option<String>,
+    pub corrections: Vec<Correction>,
 }
 
 impl ROM {
-    pub fn new(raw: &Vec<u8>) -> Result<ROM, String> {
-        if &raw[0..4] != NES_TAG {
+    // Never panics on malformed input. Recoverable header problems are fixed
+    // up and listed in `warnings`, and header values for dumps in the
+    // built-in game database are replaced and listed in `corrections`.
+    pub fn new(raw: &[u8]) -> Result<ROM, String> {
+        ROM::with_game_db(raw, true)
+    }
+
+    // Passing false keeps the header values even for known dumps
+    pub fn with_game_db(raw: &[u8], use_game_db: bool) -> Result<ROM, String> {
+        let mut rom = if unif::is_unif(raw) {
+            unif::parse(raw)?
+        } else {
+            ROM::from_header(raw)?
+        };
+        if use_game_db {
+            rom.apply_game_db(GameDb::builtin());
+        }
+        Ok(rom)
+    }
+
+    pub fn apply_game_db(&mut self, db: &GameDb) {
+        if let Some(game) = db.lookup(self.crc32, &self.sha1) {
+            self.corrections = game.apply(self);
+        }
+    }
+
+    fn from_header(raw: &[u8]) -> Result<ROM, String> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let mut warnings = vec![];
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&raw[..HEADER_SIZE]);
 
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+        // Old dumping tools stamped their name over bytes 7-15, which would
+        // otherwise be read as mapper bits
+        if header[7..]
+            .windows(DISKDUDE_TAG.len())
+            .any(|w| w == DISKDUDE_TAG)
+        {
+            header[7..].fill(0);
+            warnings.push(HeaderWarning::DiskDudeSignature);
         }
 
-        let four_screen = raw[6] & 0b1000 != 0;
-        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let header_format = if (header[7] >> 2) & 0b11 == 2 {
+            HeaderFormat::Nes2
+        } else {
+            HeaderFormat::INes
+        };
+
+        // Bytes 12-15 are unused by iNES, so anything there means the rest of
+        // the header after byte 6 can't be trusted either
+        if header_format == HeaderFormat::INes && header[12..].iter().any(|&b| b != 0) {
+            header[7..].fill(0);
+            warnings.push(HeaderWarning::GarbageInUnusedBytes);
+        }
+        let nes2 = header_format == HeaderFormat::Nes2;
+
+        let mut mapper = ((header[7] & 0b1111_0000) | (header[6] >> 4)) as u16;
+        let mut submapper = 0;
+        if nes2 {
+            mapper |= ((header[8] & 0b1111) as u16) << 8;
+            submapper = header[8] >> 4;
+        }
+
+        let four_screen = header[6] & 0b1000 != 0;
+        let vertical_mirroring = header[6] & 0b1 != 0;
         let mirroring = match (four_screen, vertical_mirroring) {
+            // UNROM 512 reuses the four-screen bit without the vertical bit
+            // for one-screen mirroring picked by the mapper
+            (true, false) if mapper == 30 => Mirroring::SingleScreenLower,
             (true, _) => Mirroring::FourScreen,
             (false, true) => Mirroring::Vertical,
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let battery = header[6] & 0b10 != 0;
+
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            (
+                nes2_rom_size(header[4], header[9] & 0b1111, PRG_ROM_PAGE_SIZE)?,
+                nes2_rom_size(header[5], header[9] >> 4, CHR_ROM_PAGE_SIZE)?,
+            )
+        } else {
+            (
+                header[4] as usize * PRG_ROM_PAGE_SIZE,
+                header[5] as usize * CHR_ROM_PAGE_SIZE,
+            )
+        };
+
+        let (prg_ram_size, prg_nvram_size, mut chr_ram_size, chr_nvram_size) = if nes2 {
+            (
+                nes2_ram_size(header[10] & 0b1111),
+                nes2_ram_size(header[10] >> 4),
+                nes2_ram_size(header[11] & 0b1111),
+                nes2_ram_size(header[11] >> 4),
+            )
+        } else {
+            // A size of 0 means 8KB for compatibility with older dumps
+            let size = header[8].max(1) as usize * PRG_RAM_PAGE_SIZE;
+            if battery {
+                (0, size, 0, 0)
+            } else {
+                (size, 0, 0, 0)
+            }
+        };
+
+        // Boards without CHR-ROM have RAM instead. iNES can't say how much,
+        // so assume the usual 8K.
+        if chr_rom_size == 0 && chr_ram_size + chr_nvram_size == 0 {
+            chr_ram_size = CHR_RAM_SIZE;
+        }
+
+        let timing = if nes2 {
+            match header[12] & 0b11 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            }
+        } else {
+            Timing::Ntsc
+        };
+
+        let console_type = match header[7] & 0b11 {
+            1 if nes2 => ConsoleType::VsSystem {
+                ppu_type: header[13] & 0b1111,
+                hardware_type: header[13] >> 4,
+            },
+            1 => ConsoleType::VsSystem {
+                ppu_type: 0,
+                hardware_type: 0,
+            },
+            2 => ConsoleType::Playchoice10,
+            3 if nes2 => ConsoleType::Extended(header[13] & 0b1111),
+            _ => ConsoleType::Nes,
+        };
+
+        let (misc_rom_count, expansion_device) = if nes2 {
+            (header[14] & 0b11, header[15] & 0b11_1111)
+        } else {
+            (0, 0)
+        };
 
-        let skip_trainer = raw[6] & 0b100 != 0;
+        if prg_rom_size == 0 {
+            return Err("Header declares no PRG-ROM".to_string());
+        }
+        if prg_rom_size > MAX_ROM_SIZE || chr_rom_size > MAX_ROM_SIZE {
+            return Err("Header declares an impossibly large ROM".to_string());
+        }
+
+        let data = &raw[HEADER_SIZE..];
+        let mut has_trainer = header[6] & 0b100 != 0;
+
+        // A trainer flag on a file that is exactly the declared size without
+        // one is a bad header bit, not a missing trainer
+        if has_trainer && data.len() == prg_rom_size + chr_rom_size {
+            has_trainer = false;
+            warnings.push(HeaderWarning::MissingTrainer);
+        }
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let prg_rom_start = if has_trainer { TRAINER_SIZE } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let chr_rom_end = chr_rom_start + chr_rom_size;
+
+        if data.len() < chr_rom_start {
+            return Err(format!(
+                "File is truncated: expected {} bytes of PRG-ROM, found {}",
+                prg_rom_size,
+                data.len().saturating_sub(prg_rom_start)
+            ));
+        }
+        let trainer = data[..prg_rom_start].to_vec();
+        let prg_rom = data[prg_rom_start..chr_rom_start].to_vec();
+
+        // Missing CHR is padded out to a whole bank so the game can still run
+        // with glitched graphics. Padding to the declared size would let a
+        // bad header allocate megabytes of nothing.
+        let mut chr_rom = data[chr_rom_start..data.len().min(chr_rom_end)].to_vec();
+        if chr_rom.len() < chr_rom_size {
+            warnings.push(HeaderWarning::TruncatedChrRom {
+                expected: chr_rom_size,
+                found: chr_rom.len(),
+            });
+            let banks = chr_rom.len().div_ceil(CHR_ROM_PAGE_SIZE).max(1);
+            chr_rom.resize(banks * CHR_ROM_PAGE_SIZE, 0);
+        }
+
+        let mut trailing = data.get(chr_rom_end..).unwrap_or(&[]);
+
+        let mut inst_rom = vec![];
+        let mut playchoice_prom = vec![];
+        if console_type == ConsoleType::Playchoice10 {
+            if trailing.len() >= INST_ROM_SIZE {
+                inst_rom = trailing[..INST_ROM_SIZE].to_vec();
+                trailing = &trailing[INST_ROM_SIZE..];
+
+                let prom_len = trailing.len().min(PLAYCHOICE_PROM_SIZE);
+                playchoice_prom = trailing[..prom_len].to_vec();
+                trailing = &trailing[prom_len..];
+            } else {
+                warnings.push(HeaderWarning::MissingInstRom);
+            }
+        }
+
+        let mut misc_rom = vec![];
+        if misc_rom_count > 0 {
+            misc_rom = trailing.to_vec();
+            trailing = &[];
+        }
+
+        if !trailing.is_empty() {
+            warnings.push(HeaderWarning::TrailingData {
+                len: trailing.len(),
+            });
+        }
+
+        let (crc32, sha1) = rom_hashes(&prg_rom, &chr_rom);
 
         Ok(ROM {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            crc32,
+            sha1,
+            board: None,
+            corrections: vec![],
+            prg_rom,
+            chr_rom,
+            header_format,
             mapper,
+            submapper,
             mirroring,
+            battery,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            timing,
+            console_type,
+            misc_rom_count,
+            expansion_device,
+            inst_rom,
+            playchoice_prom,
+            misc_rom,
+            trainer,
+            warnings,
         })
     }
+
+    // Total work RAM at $6000-$7FFF, whether or not it is battery-backed
+    pub fn work_ram_size(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
+}
+
+// CRC32 and SHA-1 of PRG-ROM followed by CHR-ROM
+pub(crate) fn rom_hashes(prg_rom: &[u8], chr_rom: &[u8]) -> (u32, [u8; 20]) {
+    let mut crc32 = Crc32::new();
+    let mut sha1 = Sha1::new();
+    for data in [prg_rom, chr_rom] {
+        crc32.update(data);
+        sha1.update(data);
+    }
+    (crc32.finish(), sha1.finish())
+}
+
+// NES 2.0 ROM sizes are either a 12-bit page count, or when the upper nibble
+// is $F, an exponent-multiplier pair: 2^E * (MM * 2 + 1) bytes
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Result<usize, String> {
+    if msb == 0b1111 {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        return 1usize
+            .checked_shl(exponent)
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| format!("ROM size 2^{} * {} is too large", exponent, multiplier));
+    }
+
+    Ok((((msb as usize) << 8) | lsb as usize) * page_size)
+}
+
+// RAM sizes are shift counts: 64 << n bytes, with 0 meaning none
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Header followed by `len` bytes of data
+    fn image(header: [u8; 16], len: usize) -> Vec<u8> {
+        let mut raw = header.to_vec();
+        raw.resize(HEADER_SIZE + len, 0);
+        raw
+    }
+
+    fn nes2_header(prg: u8, chr: u8, size_msb: u8, prg_ram: u8, chr_ram: u8) -> [u8; 16] {
+        [
+            0x4e, 0x45, 0x53, 0x1a, prg, chr, 0, 0b1000, 0, size_msb, prg_ram, chr_ram, 0, 0, 0, 0,
+        ]
+    }
+
+    fn ines_header(prg: u8, chr: u8, flags6: u8, flags7: u8) -> [u8; 16] {
+        [
+            0x4e, 0x45, 0x53, 0x1a, prg, chr, flags6, flags7, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]
+    }
+
+    fn parse(raw: &[u8]) -> ROM {
+        ROM::with_game_db(raw, false).unwrap()
+    }
+
+    fn parse_error(raw: &[u8]) -> String {
+        ROM::with_game_db(raw, false).err().unwrap()
+    }
+
+    #[test]
+    fn test_nes2_page_count_rom_sizes() {
+        let rom = parse(&image(nes2_header(2, 1, 0, 0, 0), 0xa000));
+        assert_eq!(rom.header_format, HeaderFormat::Nes2);
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!(rom.chr_rom.len(), 0x2000);
+
+        // The MSB nibbles extend the page counts to 12 bits
+        let raw = image(nes2_header(0, 0, 0x01, 0, 0), 0x100 * 0x4000);
+        assert_eq!(parse(&raw).prg_rom.len(), 0x100 * 0x4000);
+    }
+
+    #[test]
+    fn test_nes2_exponent_multiplier_rom_sizes() {
+        // PRG 2^14 * 3 and CHR 2^13 * 1
+        let header = nes2_header((14 << 2) | 1, 13 << 2, 0xff, 0, 0);
+        let rom = parse(&image(header, 0xc000 + 0x2000));
+        assert_eq!(rom.prg_rom.len(), 0xc000);
+        assert_eq!(rom.chr_rom.len(), 0x2000);
+
+        let header = nes2_header((63 << 2) | 3, 0, 0x0f, 0, 0);
+        assert!(parse_error(&image(header, 0)).contains("too large"));
+        let header = nes2_header(40 << 2, 0, 0x0f, 0, 0);
+        assert!(parse_error(&image(header, 0)).contains("impossibly large"));
+    }
+
+    #[test]
+    fn test_nes2_ram_sizes_are_shift_counts() {
+        let rom = parse(&image(nes2_header(1, 0, 0, 0x70, 0x07), 0x4000));
+        assert_eq!(rom.prg_ram_size, 0);
+        assert_eq!(rom.prg_nvram_size, 0x2000);
+        assert_eq!(rom.chr_ram_size, 0x2000);
+        assert_eq!(rom.chr_nvram_size, 0);
+
+        let rom = parse(&image(nes2_header(1, 1, 0, 0x09, 0x01), 0x6000));
+        assert_eq!(rom.prg_ram_size, 0x8000);
+        assert_eq!(rom.chr_ram_size, 128);
+    }
+
+    #[test]
+    fn test_ines_ram_size_follows_battery_flag() {
+        let rom = parse(&image(ines_header(1, 1, 0, 0), 0x6000));
+        assert_eq!((rom.prg_ram_size, rom.prg_nvram_size), (0x2000, 0));
+
+        let rom = parse(&image(ines_header(1, 0, 0b10, 0), 0x4000));
+        assert_eq!((rom.prg_ram_size, rom.prg_nvram_size), (0, 0x2000));
+        assert_eq!(rom.chr_ram_size, CHR_RAM_SIZE);
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        assert!(parse_error(&[0x4e, 0x45, 0x53]).contains("iNES"));
+        assert!(parse_error(&image(ines_header(0, 1, 0, 0), 0x2000)).contains("no PRG-ROM"));
+
+        let error = parse_error(&image(ines_header(2, 1, 0, 0), 0x4000));
+        assert_eq!(
+            error,
+            "File is truncated: expected 32768 bytes of PRG-ROM, found 16384"
+        );
+
+        // Short CHR is padded to a whole bank instead
+        let rom = parse(&image(ines_header(1, 255, 0, 0), 0x4000 + 0x2100));
+        assert_eq!(rom.chr_rom.len(), 0x4000);
+        let rom = parse(&image(ines_header(1, 2, 0, 0), 0x4000 + 0x1000));
+        assert_eq!(rom.chr_rom.len(), 0x2000);
+        assert_eq!(
+            rom.warnings,
+            [HeaderWarning::TruncatedChrRom {
+                expected: 0x4000,
+                found: 0x1000
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diskdude_signature_is_cleared() {
+        let mut header = ines_header(1, 1, 0x10, 0);
+        header[7..].copy_from_slice(b"DiskDude!");
+        let rom = parse(&image(header, 0x6000));
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.header_format, HeaderFormat::INes);
+        assert_eq!(rom.warnings, [HeaderWarning::DiskDudeSignature]);
+    }
+
+    #[test]
+    fn test_garbage_in_unused_bytes_drops_upper_mapper_nibble() {
+        let mut header = ines_header(1, 1, 0x10, 0x40);
+        header[12..].copy_from_slice(b"ABCD");
+        let rom = parse(&image(header, 0x6000));
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.warnings, [HeaderWarning::GarbageInUnusedBytes]);
+    }
+
+    #[test]
+    fn test_trainer_flag_without_trainer() {
+        let rom = parse(&image(ines_header(1, 1, 0b100, 0), 0x6000));
+        assert!(rom.trainer.is_empty());
+        assert_eq!(rom.warnings, [HeaderWarning::MissingTrainer]);
+
+        let mut raw = image(ines_header(1, 1, 0b100, 0), TRAINER_SIZE + 0x6000);
+        raw[HEADER_SIZE] = 0x42;
+        let rom = parse(&raw);
+        assert_eq!(rom.trainer.len(), TRAINER_SIZE);
+        assert_eq!(rom.trainer[0], 0x42);
+        assert!(rom.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_trailing_data_and_missing_inst_rom() {
+        let rom = parse(&image(ines_header(1, 1, 0, 0), 0x6000 + 100));
+        assert_eq!(rom.warnings, [HeaderWarning::TrailingData { len: 100 }]);
+        assert_eq!(
+            rom.warnings[0].to_string(),
+            "100 bytes of unknown data after CHR-ROM"
+        );
+
+        let rom = parse(&image(ines_header(1, 1, 0, 0b10), 0x6000));
+        assert_eq!(rom.console_type, ConsoleType::Playchoice10);
+        assert_eq!(rom.warnings, [HeaderWarning::MissingInstRom]);
+
+        let rom = parse(&image(
+            ines_header(1, 1, 0, 0b10),
+            0x6000 + INST_ROM_SIZE + PLAYCHOICE_PROM_SIZE,
+        ));
+        assert_eq!(rom.inst_rom.len(), INST_ROM_SIZE);
+        assert_eq!(rom.playchoice_prom.len(), PLAYCHOICE_PROM_SIZE);
+        assert!(rom.warnings.is_empty());
+    }
 }
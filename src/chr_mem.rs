@@ -0,0 +1,70 @@
+use crate::mapper::bank_offset;
+use crate::savestate::{StateReader, StateWriter};
+
+// Pattern memory at PPU $0000-$1FFF. Boards without CHR-ROM have RAM in its
+// place, which games fill with tiles at runtime.
+pub struct ChrMem {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl ChrMem {
+    // Falls back to `ram_size` bytes of RAM when there is no CHR-ROM
+    pub fn new(chr_rom: Vec<u8>, ram_size: usize) -> Self {
+        if chr_rom.is_empty() {
+            ChrMem {
+                data: vec![0; ram_size],
+                writable: true,
+            }
+        } else {
+            ChrMem {
+                data: chr_rom,
+                writable: false,
+            }
+        }
+    }
+
+    pub fn is_ram(&self) -> bool {
+        self.writable
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Byte at `addr` inside a window of `bank_size` bytes showing `bank`
+    pub fn read(&self, bank: usize, bank_size: usize, addr: u16) -> u8 {
+        if self.data.is_empty() {
+            return 0;
+        }
+
+        self.data[bank_offset(self.data.len(), bank, bank_size, addr)]
+    }
+
+    pub fn write(&mut self, bank: usize, bank_size: usize, addr: u16, data: u8) {
+        if !self.writable || self.data.is_empty() {
+            return;
+        }
+
+        let offset = bank_offset(self.data.len(), bank, bank_size, addr);
+        self.data[offset] = data;
+    }
+
+    // Only RAM is saved, ROM comes back from the cartridge
+    pub fn save_state(&self, state: &mut StateWriter) {
+        if self.writable {
+            state.write_bytes(&self.data);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        if self.writable {
+            state.read_bytes_into(&mut self.data)?;
+        }
+        Ok(())
+    }
+}
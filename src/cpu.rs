@@ -46,10 +46,10 @@ pub struct CPU {
 }
 
 pub trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+    fn mem_read(&mut self, addr: u16) -> u8;
     fn mem_write(&mut self, addr: u16, data: u8);
 
-    fn mem_read_u16(&self, addr: u16) -> u16 {
+    fn mem_read_u16(&mut self, addr: u16) -> u16 {
         let lo = self.mem_read(addr) as u16;
         let hi = self.mem_read(addr + 1) as u16;
         (hi << 8) | lo
@@ -64,7 +64,7 @@ pub trait Mem {
 }
 
 impl Mem for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         self.bus.mem_read(addr)
     }
 
@@ -72,7 +72,7 @@ impl Mem for CPU {
         self.bus.mem_write(addr, data)
     }
 
-    fn mem_read_u16(&self, addr: u16) -> u16 {
+    fn mem_read_u16(&mut self, addr: u16) -> u16 {
         self.bus.mem_read_u16(addr)
     }
 
@@ -123,7 +123,7 @@ impl CPU {
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
-    fn stack_push_u16(&mut self, value: u16) {
+    pub(crate) fn stack_push_u16(&mut self, value: u16) {
         let hi = (value >> 8) as u8;
         let lo = (value & 0xff) as u8;
         self.stack_push(hi);
@@ -149,9 +149,14 @@ impl CPU {
     where
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: HashMap<u8, &'static opcode::OpCode> = *opcode::OP_CODE_MAP;
+        let opcodes: &HashMap<u8, &'static opcode::OpCode> = &opcode::OP_CODE_MAP;
 
         loop {
+            self.bus.begin_instruction(self.program_counter);
+            if self.bus.watchpoints.is_paused() {
+                return;
+            }
+
             let code = self.mem_read(self.program_counter);
             self.program_counter += 1;
             let program_counter_state = self.program_counter;
@@ -317,7 +322,18 @@ impl CPU {
                 self.program_counter += (opcode.len - 1) as u16;
             }
 
+            self.bus.tick(opcode.cycles);
+
+            if self.bus.irq_pending() && !self.status.contains(CPUFlags::INTERRUPT_DISABLE) {
+                self.irq();
+                self.bus.tick(7);
+            }
+
             callback(self);
+
+            if self.bus.watchpoints.is_paused() {
+                return;
+            }
         }
     }
 
@@ -373,7 +389,7 @@ impl CPU {
 
     fn dec(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_addressing(mode);
-        let value = self.mem_read(addr).wrapping_sub(1);
+        let value = self.read_modify_write(addr).wrapping_sub(1);
         self.mem_write(addr, value);
         self.update_zero_and_set_negative_flags(value);
     }
@@ -390,7 +406,7 @@ impl CPU {
 
     fn inc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_addressing(mode);
-        let value = self.mem_read(addr).wrapping_add(1);
+        let value = self.read_modify_write(addr).wrapping_add(1);
         self.mem_write(addr, value);
         self.update_zero_and_set_negative_flags(value);
     }
@@ -406,19 +422,23 @@ impl CPU {
     }
 
     fn sta(&mut self, mode: &AddressingMode) {
-        self.mem_write(self.get_operand_addressing(mode), self.register_a);
+        let addr = self.get_operand_addressing(mode);
+        self.mem_write(addr, self.register_a);
     }
 
     fn stx(&mut self, mode: &AddressingMode) {
-        self.mem_write(self.get_operand_addressing(mode), self.register_x);
+        let addr = self.get_operand_addressing(mode);
+        self.mem_write(addr, self.register_x);
     }
 
     fn sty(&mut self, mode: &AddressingMode) {
-        self.mem_write(self.get_operand_addressing(mode), self.register_y);
+        let addr = self.get_operand_addressing(mode);
+        self.mem_write(addr, self.register_y);
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
-        self.add_to_register_a(self.read_value_from_memory(mode));
+        let value = self.read_value_from_memory(mode);
+        self.add_to_register_a(value);
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
@@ -450,13 +470,10 @@ impl CPU {
     }
 
     fn irq(&mut self) {
-        self.status.insert(CPUFlags::INTERRUPT_DISABLE);
+        self.stack_push_u16(self.program_counter);
         self.stack_push(self.clone_status(false).bits());
-    }
-
-    fn nmi(&mut self) {
         self.status.insert(CPUFlags::INTERRUPT_DISABLE);
-        self.stack_push(self.clone_status(false).bits());
+        self.program_counter = self.mem_read_u16(0xfffe);
     }
 
     fn asl_a(&mut self) {
@@ -473,7 +490,7 @@ impl CPU {
 
     fn asl(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_addressing(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_modify_write(addr);
 
         if value >> 7 == 1 {
             self.set_carry_flag();
@@ -481,7 +498,7 @@ impl CPU {
             self.remove_carry_flag();
         }
 
-        value = value << 1;
+        value <<= 1;
         self.mem_write(addr, value);
         self.update_zero_and_set_negative_flags(value);
     }
@@ -500,7 +517,7 @@ impl CPU {
 
     fn lsr(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_addressing(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_modify_write(addr);
 
         if value & 1 == 1 {
             self.set_carry_flag();
@@ -508,7 +525,7 @@ impl CPU {
             self.remove_carry_flag();
         }
 
-        value = value >> 1;
+        value >>= 1;
         self.mem_write(addr, value);
         self.update_zero_and_set_negative_flags(value);
     }
@@ -523,10 +540,10 @@ impl CPU {
             self.remove_carry_flag();
         }
 
-        value = value << 1;
+        value <<= 1;
 
         if carry {
-            value = value | 1;
+            value |= 1;
         }
 
         self.set_register_a(value);
@@ -534,7 +551,7 @@ impl CPU {
 
     fn rol(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_addressing(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_modify_write(addr);
         let carry = self.status.contains(CPUFlags::CARRY);
 
         if value >> 7 == 1 {
@@ -543,10 +560,10 @@ impl CPU {
             self.remove_carry_flag();
         }
 
-        value = value << 1;
+        value <<= 1;
 
         if carry {
-            value = value | 1;
+            value |= 1;
         }
 
         self.mem_write(addr, value);
@@ -563,10 +580,10 @@ impl CPU {
             self.remove_carry_flag();
         }
 
-        value = value >> 1;
+        value >>= 1;
 
         if carry {
-            value = value | 0b1000_0000;
+            value |= 0b1000_0000;
         }
 
         self.set_register_a(value);
@@ -574,7 +591,7 @@ impl CPU {
 
     fn ror(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_addressing(mode);
-        let mut value = self.mem_read(addr);
+        let mut value = self.read_modify_write(addr);
         let carry = self.status.contains(CPUFlags::CARRY);
 
         if value & 1 == 1 {
@@ -583,10 +600,10 @@ impl CPU {
             self.remove_carry_flag();
         }
 
-        value = value >> 1;
+        value >>= 1;
 
         if carry {
-            value = value | 0b1000_0000;
+            value |= 0b1000_0000;
         }
 
         self.mem_write(addr, value);
@@ -686,7 +703,7 @@ impl CPU {
     }
 
     fn clone_status(&self, b: bool) -> CPUFlags {
-        let mut status = self.status.clone();
+        let mut status = self.status;
         status.insert(CPUFlags::EXPANSION);
 
         if b {
@@ -739,8 +756,18 @@ impl CPU {
         self.update_zero_and_set_negative_flags(self.register_a);
     }
 
-    fn read_value_from_memory(&self, mode: &AddressingMode) -> u8 {
-        self.mem_read(self.get_operand_addressing(mode))
+    // Read-modify-write instructions write the unmodified value back before
+    // the result, which mappers can see. Write watchpoints see both writes
+    // too, the same as a logic analyzer on the real bus would.
+    fn read_modify_write(&mut self, addr: u16) -> u8 {
+        let value = self.mem_read(addr);
+        self.mem_write(addr, value);
+        value
+    }
+
+    fn read_value_from_memory(&mut self, mode: &AddressingMode) -> u8 {
+        let addr = self.get_operand_addressing(mode);
+        self.mem_read(addr)
     }
 
     fn update_negative_flags(&mut self, result: u8) {
@@ -765,7 +792,7 @@ impl CPU {
     }
 
     // http://www.emulator101.com/6502-addressing-modes.html
-    fn get_operand_addressing(&self, mode: &AddressingMode) -> u16 {
+    fn get_operand_addressing(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter,
             AddressingMode::ZeroPage => self.mem_read(self.program_counter) as u16,
@@ -785,7 +812,7 @@ impl CPU {
             AddressingMode::IndirectX => {
                 let addr = self.mem_read(self.program_counter);
 
-                let ptr = (addr as u8).wrapping_add(self.register_x);
+                let ptr = addr.wrapping_add(self.register_x);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
                 (hi as u16) << 8 | (lo as u16)
@@ -794,7 +821,7 @@ impl CPU {
                 let addr = self.mem_read(self.program_counter);
 
                 let lo = self.mem_read(addr as u16);
-                let hi = self.mem_read((addr as u8).wrapping_add(1) as u16);
+                let hi = self.mem_read(addr.wrapping_add(1) as u16);
                 let deref_base = (hi as u16) << 8 | (lo as u16);
                 deref_base.wrapping_add(self.register_y as u16)
             }
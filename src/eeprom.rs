@@ -0,0 +1,368 @@
+use crate::savestate::{StateReader, StateWriter};
+
+// Serial EEPROMs on Bandai boards. The game bit-bangs the two I2C lines
+// through a mapper register and the chip follows along one clock edge at a
+// time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EepromChip {
+    // 128 bytes. No device address: the first byte after a start is the
+    // word address and read bit, and bytes go least significant bit first.
+    X24C01,
+    // 256 bytes, standard I2C: device address $A0/$A1, then the word
+    // address, most significant bit first
+    C24C02,
+}
+
+impl EepromChip {
+    pub fn size(&self) -> usize {
+        match self {
+            EepromChip::X24C01 => 128,
+            EepromChip::C24C02 => 256,
+        }
+    }
+
+    // Bytes one write can cover before the address wraps within the page
+    fn page_size(&self) -> u8 {
+        match self {
+            EepromChip::X24C01 => 4,
+            EepromChip::C24C02 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Device,
+    Address,
+    Write,
+    Read,
+}
+
+impl Phase {
+    fn from_u8(value: u8) -> Phase {
+        match value {
+            1 => Phase::Device,
+            2 => Phase::Address,
+            3 => Phase::Write,
+            4 => Phase::Read,
+            _ => Phase::Idle,
+        }
+    }
+}
+
+pub struct Eeprom {
+    chip: EepromChip,
+    data: Vec<u8>,
+    phase: Phase,
+    // Bits of the current byte clocked so far. 8 is the acknowledge clock.
+    bit: u8,
+    shift: u8,
+    address: u8,
+    scl: bool,
+    sda: bool,
+    // What the chip drives on SDA. High means released.
+    output: bool,
+}
+
+impl Eeprom {
+    pub fn new(chip: EepromChip) -> Self {
+        Eeprom {
+            chip,
+            data: vec![0xff; chip.size()],
+            phase: Phase::Idle,
+            bit: 0,
+            shift: 0,
+            address: 0,
+            scl: false,
+            sda: false,
+            output: true,
+        }
+    }
+
+    pub fn chip(&self) -> EepromChip {
+        self.chip
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Restores contents from a save file; a short file only fills the start
+    pub fn load(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+
+    // Level the chip drives on SDA
+    pub fn read(&self) -> bool {
+        self.output
+    }
+
+    pub fn write_lines(&mut self, scl: bool, sda: bool) {
+        if self.scl && scl && sda != self.sda {
+            // SDA only changes with SCL high to signal start or stop
+            if sda {
+                self.stop();
+            } else {
+                self.start();
+            }
+        } else if !self.scl && scl {
+            self.clock_rise(sda);
+        } else if self.scl && !scl {
+            self.clock_fall();
+        }
+
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    pub fn write_scl(&mut self, scl: bool) {
+        self.write_lines(scl, self.sda);
+    }
+
+    pub fn write_sda(&mut self, sda: bool) {
+        self.write_lines(self.scl, sda);
+    }
+
+    fn start(&mut self) {
+        self.phase = match self.chip {
+            EepromChip::X24C01 => Phase::Address,
+            EepromChip::C24C02 => Phase::Device,
+        };
+        self.bit = 0;
+        self.shift = 0;
+        self.output = true;
+    }
+
+    fn stop(&mut self) {
+        self.phase = Phase::Idle;
+        self.output = true;
+    }
+
+    fn clock_rise(&mut self, sda: bool) {
+        match self.phase {
+            Phase::Idle => {}
+            Phase::Read if self.bit < 8 => self.bit += 1,
+            Phase::Read => {
+                // The master acknowledges to ask for another byte, and
+                // leaves SDA high when it is done
+                if sda {
+                    self.phase = Phase::Idle;
+                } else {
+                    self.address = self.next_address(self.address, self.chip.size() as u8);
+                    self.shift = self.data[self.address as usize];
+                    self.bit = 0;
+                }
+            }
+            _ if self.bit < 8 => {
+                match self.chip {
+                    EepromChip::X24C01 => self.shift |= (sda as u8) << self.bit,
+                    EepromChip::C24C02 => self.shift = (self.shift << 1) | sda as u8,
+                }
+                self.bit += 1;
+            }
+            // Acknowledge clock, with our ACK already on the bus
+            _ => self.bit = 9,
+        }
+    }
+
+    fn clock_fall(&mut self) {
+        match self.phase {
+            Phase::Idle => self.output = true,
+            Phase::Read => {
+                self.output = if self.bit < 8 {
+                    self.output_bit(self.bit)
+                } else {
+                    true
+                };
+            }
+            _ if self.bit == 8 => {
+                // Only answer our own device address
+                if self.phase == Phase::Device && self.shift & 0xf0 != 0xa0 {
+                    self.phase = Phase::Idle;
+                } else {
+                    self.output = false;
+                }
+            }
+            _ if self.bit == 9 => {
+                self.output = true;
+                let byte = self.shift;
+                self.bit = 0;
+                self.shift = 0;
+                self.receive(byte);
+            }
+            _ => {}
+        }
+    }
+
+    fn output_bit(&self, bit: u8) -> bool {
+        match self.chip {
+            EepromChip::X24C01 => (self.shift >> bit) & 1 != 0,
+            EepromChip::C24C02 => (self.shift >> (7 - bit)) & 1 != 0,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match (self.phase, self.chip) {
+            (Phase::Device, _) if byte & 1 != 0 => self.begin_read(),
+            (Phase::Device, _) => self.phase = Phase::Address,
+            (Phase::Address, EepromChip::X24C01) => {
+                self.address = byte & 0x7f;
+                if byte & 0x80 != 0 {
+                    self.begin_read();
+                } else {
+                    self.phase = Phase::Write;
+                }
+            }
+            (Phase::Address, EepromChip::C24C02) => {
+                self.address = byte;
+                self.phase = Phase::Write;
+            }
+            (Phase::Write, _) => {
+                let index = self.address as usize % self.data.len();
+                self.data[index] = byte;
+                self.address = self.next_address(self.address, self.chip.page_size());
+            }
+            _ => {}
+        }
+    }
+
+    fn begin_read(&mut self) {
+        self.phase = Phase::Read;
+        self.shift = self.data[self.address as usize % self.data.len()];
+        // The acknowledge clock just ended, so the first bit goes out now
+        self.output = self.output_bit(0);
+    }
+
+    // Addresses wrap within a block of `block` bytes
+    fn next_address(&self, address: u8, block: u8) -> u8 {
+        let mask = block.wrapping_sub(1);
+        (address & !mask) | (address.wrapping_add(1) & mask)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.phase as u8);
+        state.write_u8(self.bit);
+        state.write_u8(self.shift);
+        state.write_u8(self.address);
+        state.write_bool(self.scl);
+        state.write_bool(self.sda);
+        state.write_bool(self.output);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes_into(&mut self.data)?;
+        self.phase = Phase::from_u8(state.read_u8()?);
+        self.bit = state.read_u8()?;
+        self.shift = state.read_u8()?;
+        self.address = state.read_u8()? % self.data.len() as u8;
+        self.scl = state.read_bool()?;
+        self.sda = state.read_bool()?;
+        self.output = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Drives an EEPROM the way a game's save routine does, for tests
+#[cfg(test)]
+pub(crate) mod host {
+    use super::*;
+
+    pub fn start(eeprom: &mut Eeprom) {
+        eeprom.write_lines(false, true);
+        eeprom.write_lines(true, true);
+        eeprom.write_lines(true, false);
+        eeprom.write_lines(false, false);
+    }
+
+    pub fn stop(eeprom: &mut Eeprom) {
+        eeprom.write_lines(false, false);
+        eeprom.write_lines(true, false);
+        eeprom.write_lines(true, true);
+    }
+
+    // Returns whether the chip acknowledged
+    pub fn send(eeprom: &mut Eeprom, byte: u8, lsb_first: bool) -> bool {
+        for i in 0..8 {
+            let bit = if lsb_first { i } else { 7 - i };
+            let sda = (byte >> bit) & 1 != 0;
+            eeprom.write_lines(false, sda);
+            eeprom.write_lines(true, sda);
+            eeprom.write_lines(false, sda);
+        }
+        eeprom.write_lines(false, true);
+        eeprom.write_lines(true, true);
+        let ack = !eeprom.read();
+        eeprom.write_lines(false, true);
+        ack
+    }
+
+    pub fn receive(eeprom: &mut Eeprom, lsb_first: bool, ack: bool) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            eeprom.write_lines(false, true);
+            eeprom.write_lines(true, true);
+            let bit = if lsb_first { i } else { 7 - i };
+            byte |= (eeprom.read() as u8) << bit;
+            eeprom.write_lines(false, true);
+        }
+        eeprom.write_lines(false, !ack);
+        eeprom.write_lines(true, !ack);
+        eeprom.write_lines(false, !ack);
+        byte
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::host::*;
+    use super::*;
+
+    #[test]
+    fn test_24c02_page_write_and_random_read() {
+        let mut eeprom = Eeprom::new(EepromChip::C24C02);
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0xa0, false));
+        assert!(send(&mut eeprom, 0x06, false));
+        assert!(send(&mut eeprom, 0x11, false));
+        assert!(send(&mut eeprom, 0x22, false));
+        // Wraps to the start of the 8 byte page
+        assert!(send(&mut eeprom, 0x33, false));
+        stop(&mut eeprom);
+        assert_eq!(
+            eeprom.data()[0..8],
+            [0x33, 0xff, 0xff, 0xff, 0xff, 0xff, 0x11, 0x22]
+        );
+
+        // Set the address with a dummy write, then read from it
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0xa0, false));
+        assert!(send(&mut eeprom, 0x06, false));
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0xa1, false));
+        assert_eq!(receive(&mut eeprom, false, true), 0x11);
+        assert_eq!(receive(&mut eeprom, false, false), 0x22);
+        stop(&mut eeprom);
+
+        // Other device addresses are ignored
+        start(&mut eeprom);
+        assert!(!send(&mut eeprom, 0x50, false));
+    }
+
+    #[test]
+    fn test_24c01_address_byte_carries_read_bit() {
+        let mut eeprom = Eeprom::new(EepromChip::X24C01);
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0x10, true));
+        assert!(send(&mut eeprom, 0xab, true));
+        stop(&mut eeprom);
+        assert_eq!(eeprom.data()[0x10], 0xab);
+
+        start(&mut eeprom);
+        assert!(send(&mut eeprom, 0x80 | 0x10, true));
+        assert_eq!(receive(&mut eeprom, true, false), 0xab);
+        stop(&mut eeprom);
+    }
+}
@@ -0,0 +1,347 @@
+// Famicom Disk System images. Sides are kept in .fds layout: the blocks one
+// after another with no gaps or CRCs. The drive works on the raw stream as it
+// passes under the head, so sides are converted to and from that form.
+
+const FDS_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1a];
+const FDS_HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+const QD_SIDE_SIZE: usize = 0x10000;
+const DISK_INFO_TAG: &[u8] = b"\x01*NINTENDO-HVC*";
+
+const DISK_INFO_SIZE: usize = 56;
+const FILE_COUNT_SIZE: usize = 2;
+const FILE_HEADER_SIZE: usize = 16;
+const CRC_SIZE: usize = 2;
+
+// Zero bytes before the first block and between blocks, as the BIOS writes
+// them (28300 and 976 bits)
+pub(crate) const LEAD_IN_GAP: usize = 28300 / 8;
+pub(crate) const BLOCK_GAP: usize = 976 / 8;
+// A little more than a full side plus its gaps, so the head always reaches
+// the end of the data before the end of the disk
+pub(crate) const RAW_SIDE_SIZE: usize = 0x12000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiskFormat {
+    Fds { header: bool },
+    // Dumps straight from the disk, with CRCs and a 64K side
+    Qd,
+}
+
+pub struct FdsDisk {
+    pub format: DiskFormat,
+    pub sides: Vec<Vec<u8>>,
+}
+
+pub fn is_disk_image(raw: &[u8]) -> bool {
+    raw.starts_with(&FDS_TAG) || raw.starts_with(DISK_INFO_TAG)
+}
+
+impl FdsDisk {
+    pub fn parse(raw: &[u8]) -> Result<FdsDisk, String> {
+        if raw.starts_with(&FDS_TAG) {
+            if raw.len() < FDS_HEADER_SIZE {
+                return Err("FDS header is truncated".to_string());
+            }
+            let sides = split_sides(&raw[FDS_HEADER_SIZE..], SIDE_SIZE)?;
+            return Ok(FdsDisk {
+                format: DiskFormat::Fds { header: true },
+                sides,
+            });
+        }
+
+        if !raw.starts_with(DISK_INFO_TAG) {
+            return Err("File is not a Famicom Disk System image".to_string());
+        }
+
+        // Block 2 follows block 1 directly in .fds, and after its CRC in .qd
+        if raw.get(DISK_INFO_SIZE + CRC_SIZE) == Some(&2) && raw.get(DISK_INFO_SIZE) != Some(&2) {
+            let sides = split_sides(raw, QD_SIDE_SIZE)?
+                .iter()
+                .map(|side| strip_crcs(side))
+                .collect();
+            return Ok(FdsDisk {
+                format: DiskFormat::Qd,
+                sides,
+            });
+        }
+
+        Ok(FdsDisk {
+            format: DiskFormat::Fds { header: false },
+            sides: split_sides(raw, SIDE_SIZE)?,
+        })
+    }
+
+    // The image in the format it was loaded from
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self.format {
+            DiskFormat::Fds { header } => {
+                if header {
+                    out.extend_from_slice(&FDS_TAG);
+                    out.push(self.sides.len() as u8);
+                    out.resize(FDS_HEADER_SIZE, 0);
+                }
+                for side in &self.sides {
+                    out.extend_from_slice(side);
+                }
+            }
+            DiskFormat::Qd => {
+                for side in &self.sides {
+                    let start = out.len();
+                    for block in side_blocks(side) {
+                        out.extend_from_slice(block);
+                        out.extend_from_slice(&crc(block).to_le_bytes());
+                    }
+                    out.resize(start + QD_SIDE_SIZE, 0);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn split_sides(data: &[u8], side_size: usize) -> Result<Vec<Vec<u8>>, String> {
+    if data.is_empty() {
+        return Err("Disk image has no sides".to_string());
+    }
+
+    // A short last side is padded rather than rejected, since some tools
+    // trim trailing zeroes
+    Ok(data
+        .chunks(side_size)
+        .map(|chunk| {
+            let mut side = chunk.to_vec();
+            side.resize(side_size, 0);
+            side
+        })
+        .collect())
+}
+
+// Walks the blocks of a side in .fds layout, stopping at the first byte that
+// doesn't start the block expected next
+fn side_blocks(side: &[u8]) -> Vec<&[u8]> {
+    let mut blocks = vec![];
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    while let Some(&code) = side.get(pos) {
+        let len = match (blocks.len(), code) {
+            (0, 1) => DISK_INFO_SIZE,
+            (1, 2) => FILE_COUNT_SIZE,
+            (n, 3) if n >= 2 && n % 2 == 0 => FILE_HEADER_SIZE,
+            (n, 4) if n >= 3 && n % 2 == 1 => 1 + file_size,
+            _ => break,
+        };
+        let block = match side.get(pos..pos + len) {
+            Some(block) => block,
+            None => break,
+        };
+        if code == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        blocks.push(block);
+        pos += len;
+    }
+
+    blocks
+}
+
+// .qd side to .fds layout
+fn strip_crcs(side: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    while let Some(&code) = side.get(pos) {
+        let len = match code {
+            1 => DISK_INFO_SIZE,
+            2 => FILE_COUNT_SIZE,
+            3 => FILE_HEADER_SIZE,
+            4 => 1 + file_size,
+            _ => break,
+        };
+        let block = match side.get(pos..pos + len) {
+            Some(block) => block,
+            None => break,
+        };
+        if code == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        out.extend_from_slice(block);
+        pos += len + CRC_SIZE;
+    }
+
+    // A full .qd side can hold slightly more than an .fds one
+    out.resize(SIDE_SIZE.max(out.len()), 0);
+    out
+}
+
+// The stream under the head: a gap of zeroes, then each block behind a $80
+// start mark and followed by its CRC and another gap
+pub(crate) fn to_raw_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    for block in side_blocks(side) {
+        raw.push(0x80);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&crc(block).to_le_bytes());
+        raw.resize(raw.len() + BLOCK_GAP, 0);
+    }
+    raw.resize(RAW_SIDE_SIZE.max(raw.len()), 0);
+    raw
+}
+
+// Picks the blocks back out of a raw stream after the game wrote to it
+pub(crate) fn from_raw_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = vec![];
+    let mut pos = 0;
+    let mut expected = 1;
+    let mut file_size = 0;
+
+    loop {
+        // Skip the gap up to the start mark
+        while raw.get(pos) == Some(&0) {
+            pos += 1;
+        }
+        if raw.get(pos) != Some(&0x80) {
+            break;
+        }
+        pos += 1;
+
+        let code = match raw.get(pos) {
+            Some(&code) if code == expected => code,
+            _ => break,
+        };
+        let len = match code {
+            1 => DISK_INFO_SIZE,
+            2 => FILE_COUNT_SIZE,
+            3 => FILE_HEADER_SIZE,
+            _ => 1 + file_size,
+        };
+        let block = match raw.get(pos..pos + len) {
+            Some(block) => block,
+            None => break,
+        };
+        if code == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        side.extend_from_slice(block);
+        pos += len + CRC_SIZE;
+        expected = match code {
+            1 => 2,
+            3 => 4,
+            _ => 3,
+        };
+    }
+
+    side.resize(SIDE_SIZE.max(side.len()), 0);
+    side
+}
+
+// CRC-16 as the drive computes it: reflected CCITT, seeded by the $80 start
+// mark, and run over two extra zero bytes so the result lands in the register
+pub(crate) fn crc(block: &[u8]) -> u16 {
+    let mut crc = 0;
+    for &byte in [0x80].iter().chain(block).chain(&[0, 0]) {
+        crc = update_crc(crc, byte);
+    }
+    crc
+}
+
+pub(crate) fn update_crc(mut crc: u16, byte: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc = (crc >> 1) | (((byte >> bit) as u16 & 1) << 15);
+        if carry {
+            crc ^= 0x8408;
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+pub(crate) fn test_side(files: &[&[u8]]) -> Vec<u8> {
+    let mut side = DISK_INFO_TAG.to_vec();
+    side.resize(DISK_INFO_SIZE, 0);
+    side.extend_from_slice(&[2, files.len() as u8]);
+    for (index, file) in files.iter().enumerate() {
+        let mut header = vec![3, 0, index as u8];
+        header.extend_from_slice(b"FILE    ");
+        header.extend_from_slice(&[0, 0x60]);
+        header.extend_from_slice(&(file.len() as u16).to_le_bytes());
+        header.push(0);
+        side.extend_from_slice(&header);
+        side.push(4);
+        side.extend_from_slice(file);
+    }
+    side.resize(SIDE_SIZE.max(side.len()), 0);
+    side
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc_of_block_and_crc_is_zero() {
+        let block = [3, 0, 1, 2, 3, 4];
+        let mut register = update_crc(0, 0x80);
+        for &byte in block.iter().chain(&crc(&block).to_le_bytes()) {
+            register = update_crc(register, byte);
+        }
+        assert_eq!(register, 0);
+    }
+
+    #[test]
+    fn test_raw_side_round_trip() {
+        let side = test_side(&[b"hello", &[0xaa; 300]]);
+        let raw = to_raw_side(&side);
+        assert_eq!(raw.len(), RAW_SIDE_SIZE);
+        assert_eq!(raw[LEAD_IN_GAP - 1], 0);
+        assert_eq!(raw[LEAD_IN_GAP], 0x80);
+        assert_eq!(&raw[LEAD_IN_GAP + 1..LEAD_IN_GAP + 16], DISK_INFO_TAG);
+        assert_eq!(from_raw_side(&raw), side);
+    }
+
+    #[test]
+    fn test_parses_fds_and_qd_images() {
+        let side = test_side(&[b"data"]);
+
+        let mut fds = FDS_TAG.to_vec();
+        fds.push(1);
+        fds.resize(FDS_HEADER_SIZE, 0);
+        fds.extend_from_slice(&side);
+        let disk = FdsDisk::parse(&fds).unwrap();
+        assert_eq!(disk.format, DiskFormat::Fds { header: true });
+        assert_eq!(disk.sides, vec![side.clone()]);
+        assert_eq!(disk.to_bytes(), fds);
+
+        let qd = FdsDisk {
+            format: DiskFormat::Qd,
+            sides: vec![side.clone()],
+        }
+        .to_bytes();
+        assert_eq!(qd.len(), QD_SIDE_SIZE);
+        let disk = FdsDisk::parse(&qd).unwrap();
+        assert_eq!(disk.format, DiskFormat::Qd);
+        assert_eq!(disk.sides, vec![side]);
+    }
+
+    #[test]
+    fn test_qd_round_trip_keeps_blocks_at_end_of_side() {
+        // The file block runs past byte 65500 of the .qd side
+        let file = vec![0x5a; 65440];
+        let side = test_side(&[&file]);
+        let qd = FdsDisk {
+            format: DiskFormat::Qd,
+            sides: vec![side.clone(), side.clone()],
+        }
+        .to_bytes();
+        assert_eq!(qd.len(), 2 * QD_SIDE_SIZE);
+        assert_eq!(qd[QD_SIDE_SIZE - 20], 0x5a);
+
+        let disk = FdsDisk::parse(&qd).unwrap();
+        assert_eq!(disk.sides, vec![side.clone(), side]);
+        assert_eq!(disk.to_bytes(), qd);
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cartridge::{HeaderFormat, Mirroring, Timing, ROM};
+
+lazy_static! {
+    static ref BUILTIN: GameDb =
+        GameDb::parse(include_str!("game_db.txt")).expect("built-in game database is invalid");
+}
+
+// What a known dump actually needs, keyed by the CRC32 of PRG+CHR
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameInfo {
+    pub crc32: u32,
+    // Checked as well when present, to rule out CRC collisions
+    pub sha1: Option<[u8; 20]>,
+    pub board: String,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub prg_ram_size: usize,
+    pub battery: bool,
+    pub timing: Timing,
+}
+
+// A header value the database replaced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correction {
+    pub field: &'static str,
+    pub header: String,
+    pub database: String,
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: header says {}, database says {}",
+            self.field, self.header, self.database
+        )
+    }
+}
+
+impl GameInfo {
+    // Overwrites the header derived fields of `rom` and lists the ones that
+    // changed
+    pub fn apply(&self, rom: &mut ROM) -> Vec<Correction> {
+        let mut corrections = vec![];
+        let mut correct = |field, header: String, database: String| {
+            if header != database {
+                corrections.push(Correction {
+                    field,
+                    header,
+                    database,
+                });
+            }
+        };
+
+        correct("mapper", rom.mapper.to_string(), self.mapper.to_string());
+        correct(
+            "submapper",
+            rom.submapper.to_string(),
+            self.submapper.to_string(),
+        );
+        // Mappers that switch mirroring ignore the header bit, so only a
+        // four-screen mismatch matters for them
+        let four_screen =
+            rom.mirroring == Mirroring::FourScreen || self.mirroring == Mirroring::FourScreen;
+        if four_screen || !mapper_sets_mirroring(self.mapper) {
+            correct(
+                "mirroring",
+                format!("{:?}", rom.mirroring),
+                format!("{:?}", self.mirroring),
+            );
+        }
+        // iNES 1.0 headers almost never give a size, so 8K is only the
+        // default standing in for one
+        let default_prg_ram =
+            rom.header_format == HeaderFormat::INes && rom.work_ram_size() == 0x2000;
+        if !default_prg_ram {
+            correct(
+                "PRG-RAM",
+                rom.work_ram_size().to_string(),
+                self.prg_ram_size.to_string(),
+            );
+        }
+        correct("battery", rom.battery.to_string(), self.battery.to_string());
+        correct(
+            "timing",
+            format!("{:?}", rom.timing),
+            format!("{:?}", self.timing),
+        );
+
+        rom.mapper = self.mapper;
+        rom.submapper = self.submapper;
+        rom.mirroring = self.mirroring;
+        rom.battery = self.battery;
+        if self.battery {
+            rom.prg_ram_size = 0;
+            rom.prg_nvram_size = self.prg_ram_size;
+        } else {
+            rom.prg_ram_size = self.prg_ram_size;
+            rom.prg_nvram_size = 0;
+        }
+        rom.timing = self.timing;
+        rom.board = Some(self.board.clone());
+
+        corrections
+    }
+}
+
+fn mapper_sets_mirroring(mapper: u16) -> bool {
+    matches!(
+        mapper,
+        1 | 4 | 5 | 7 | 9 | 10 | 16 | 19 | 21 | 22 | 23 | 25 | 69 | 118 | 119 | 153 | 157 | 159
+    )
+}
+
+pub struct GameDb {
+    games: HashMap<u32, Vec<GameInfo>>,
+}
+
+impl GameDb {
+    pub fn builtin() -> &'static GameDb {
+        &BUILTIN
+    }
+
+    // One game per line, fields separated by whitespace:
+    //   crc32 sha1 board mapper submapper mirroring prg_ram battery region
+    // sha1 may be "-", mirroring is H, V or 4, battery is 0 or 1 and region
+    // is NTSC, PAL, MULTI or DENDY. Blank lines and lines starting with #
+    // are skipped.
+    pub fn parse(text: &str) -> Result<GameDb, String> {
+        let mut games: HashMap<u32, Vec<GameInfo>> = HashMap::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let game = parse_line(line).map_err(|err| format!("line {}: {}", index + 1, err))?;
+            games.entry(game.crc32).or_default().push(game);
+        }
+
+        Ok(GameDb { games })
+    }
+
+    pub fn lookup(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&GameInfo> {
+        self.games
+            .get(&crc32)?
+            .iter()
+            .find(|game| game.sha1.is_none() || game.sha1.as_ref() == Some(sha1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.values().map(|games| games.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> Result<GameInfo, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 9 {
+        return Err(format!("expected 9 fields, found {}", fields.len()));
+    }
+
+    let crc32 = u32::from_str_radix(fields[0], 16).map_err(|_| "bad CRC32".to_string())?;
+    let sha1 = match fields[1] {
+        "-" => None,
+        hex => Some(parse_sha1(hex).ok_or_else(|| "bad SHA-1".to_string())?),
+    };
+    let mirroring = match fields[5] {
+        "H" => Mirroring::Horizontal,
+        "V" => Mirroring::Vertical,
+        "4" => Mirroring::FourScreen,
+        other => return Err(format!("unknown mirroring {}", other)),
+    };
+    let timing = match fields[8] {
+        "NTSC" => Timing::Ntsc,
+        "PAL" => Timing::Pal,
+        "MULTI" => Timing::MultiRegion,
+        "DENDY" => Timing::Dendy,
+        other => return Err(format!("unknown region {}", other)),
+    };
+
+    Ok(GameInfo {
+        crc32,
+        sha1,
+        board: fields[2].to_string(),
+        mapper: fields[3].parse().map_err(|_| "bad mapper".to_string())?,
+        submapper: fields[4].parse().map_err(|_| "bad submapper".to_string())?,
+        mirroring,
+        prg_ram_size: fields[6]
+            .parse()
+            .map_err(|_| "bad PRG-RAM size".to_string())?,
+        battery: fields[7] == "1",
+        timing,
+    })
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_overrides_header_and_reports_changes() {
+        let mut rom = test_rom(1, 0, 2, 0x4000, 1, 0x2000);
+        let db = GameDb::parse(&format!(
+            "# comment\n{:08x} {} SXROM 1 0 V 8192 1 PAL\n",
+            rom.crc32,
+            crate::hash::sha1_hex(&rom.sha1)
+        ))
+        .unwrap();
+
+        let game = db.lookup(rom.crc32, &rom.sha1).unwrap().clone();
+        let corrections = game.apply(&mut rom);
+
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.prg_nvram_size, 8192);
+        assert_eq!(rom.board.as_deref(), Some("SXROM"));
+        let fields: Vec<_> = corrections.iter().map(|c| c.field).collect();
+        assert_eq!(fields, vec!["PRG-RAM", "battery", "timing"]);
+    }
+
+    #[test]
+    fn test_ines_defaults_are_not_corrections() {
+        let db = GameDb::parse(&format!(
+            "{:08x} - SNROM 1 0 H 8192 1 NTSC\n{:08x} - TVROM 4 0 4 0 0 NTSC\n",
+            1, 2
+        ))
+        .unwrap();
+
+        // No PRG-RAM size given, and MMC1 sets its own mirroring
+        let mut rom = test_rom(1, 0, 2, 0x4000, 1, 0x2000);
+        rom.header_format = HeaderFormat::INes;
+        rom.mirroring = Mirroring::Vertical;
+        rom.prg_ram_size = 0x2000;
+        let corrections = db.lookup(1, &rom.sha1).unwrap().apply(&mut rom);
+        let fields: Vec<_> = corrections.iter().map(|c| c.field).collect();
+        assert_eq!(fields, vec!["battery"]);
+        assert_eq!(rom.prg_nvram_size, 8192);
+
+        // Four-screen can't be switched by the mapper
+        let mut rom = test_rom(4, 0, 2, 0x4000, 1, 0x2000);
+        rom.header_format = HeaderFormat::INes;
+        let corrections = db.lookup(2, &rom.sha1).unwrap().apply(&mut rom);
+        let fields: Vec<_> = corrections.iter().map(|c| c.field).collect();
+        assert_eq!(fields, vec!["mirroring"]);
+    }
+
+    #[test]
+    fn test_builtin_fixes_bad_super_mario_bros_header() {
+        let db = GameDb::builtin();
+        let sha1 = parse_sha1("ea343f4e445a9050d4b4fbac2c77d0693b1d0922").unwrap();
+        let game = db.lookup(0x3337ec46, &sha1).unwrap().clone();
+
+        // A dump claiming MMC3 with horizontal mirroring and a battery
+        let mut rom = test_rom(4, 0, 2, 0x4000, 1, 0x2000);
+        rom.battery = true;
+        let corrections = game.apply(&mut rom);
+
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(!rom.battery);
+        let fields: Vec<_> = corrections.iter().map(|c| c.field).collect();
+        assert_eq!(fields, vec!["mapper", "mirroring", "battery"]);
+    }
+
+    #[test]
+    fn test_sha1_mismatch_is_not_a_match() {
+        let db =
+            GameDb::parse(&format!("00000001 {} NROM 0 0 H 0 0 NTSC", "ab".repeat(20))).unwrap();
+        assert!(db.lookup(1, &[0; 20]).is_none());
+        assert!(db.lookup(2, &[0xab; 20]).is_none());
+        assert!(db.lookup(1, &[0xab; 20]).is_some());
+    }
+
+    #[test]
+    fn test_reports_bad_lines() {
+        let err = GameDb::parse("\n00000001 - NROM 0 0 X 0 0 NTSC")
+            .err()
+            .unwrap();
+        assert_eq!(err, "line 2: unknown mirroring X");
+    }
+}
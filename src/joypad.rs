@@ -0,0 +1,145 @@
+bitflags! {
+    // Bit order matches the order the buttons are shifted out
+    pub struct JoypadButton: u8 {
+        const RIGHT    = 0b1000_0000;
+        const LEFT     = 0b0100_0000;
+        const DOWN     = 0b0010_0000;
+        const UP       = 0b0001_0000;
+        const START    = 0b0000_1000;
+        const SELECT   = 0b0000_0100;
+        const BUTTON_B = 0b0000_0010;
+        const BUTTON_A = 0b0000_0001;
+    }
+}
+
+pub struct Joypad {
+    strobe: bool,
+    // Buttons latched by the strobe, shifted out one per read
+    shift_register: u8,
+    button_status: JoypadButton,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            shift_register: 0,
+            button_status: JoypadButton::empty(),
+        }
+    }
+
+    // While strobe is high the shift register keeps reloading, so reads
+    // always return button A
+    pub fn write(&mut self, data: u8) {
+        let strobe = data & 1 == 1;
+        // The buttons held as strobe goes low are the ones that get read
+        if self.strobe || strobe {
+            self.shift_register = self.button_status.bits();
+        }
+        self.strobe = strobe;
+    }
+
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.button_status.bits() & 1;
+        }
+
+        // Official controllers shift in 1s behind the 8 buttons
+        let response = self.shift_register & 1;
+        self.shift_register = (self.shift_register >> 1) | 0b1000_0000;
+        response
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(joypad: &mut Joypad) -> Vec<u8> {
+        (0..8).map(|_| joypad.read()).collect()
+    }
+
+    #[test]
+    fn test_shifts_buttons_out_in_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButton::BUTTON_A | JoypadButton::START | JoypadButton::RIGHT);
+        joypad.write(1);
+        joypad.write(0);
+        // A, B, Select, Start, Up, Down, Left, Right
+        assert_eq!(read_all(&mut joypad), [1, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_reads_after_eighth_button_return_1() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_all(&mut joypad), [0; 8]);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+    }
+
+    #[test]
+    fn test_strobe_high_keeps_returning_a() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        joypad.write(1);
+        assert_eq!(read_all(&mut joypad), [1; 8]);
+
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, false);
+        assert_eq!(joypad.read(), 0);
+
+        // Dropping the strobe starts shifting from A again
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.read(), 1);
+    }
+
+    #[test]
+    fn test_presses_after_strobe_wait_for_the_next_one() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        joypad.set_button_pressed_status(JoypadButton::SELECT, true);
+        assert_eq!(read_all(&mut joypad), [0, 0, 0, 0, 0, 0, 0, 1]);
+
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_all(&mut joypad)[..3], [0, 1, 1]);
+    }
+
+    #[test]
+    fn test_strobe_restarts_partial_read() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButton::BUTTON_A);
+        joypad.write(0);
+        joypad.read();
+        joypad.read();
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.buttons(), JoypadButton::BUTTON_A);
+    }
+}
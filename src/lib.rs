@@ -0,0 +1,25 @@
+pub mod battery;
+pub mod bus;
+pub mod cartridge;
+pub mod chr_mem;
+pub mod cpu;
+pub mod eeprom;
+pub mod fds;
+pub mod game_db;
+pub mod hash;
+pub mod joypad;
+pub mod mapper;
+pub mod nsf;
+pub mod opcode;
+pub mod patch;
+pub mod prg_ram;
+pub mod rom_info;
+pub mod savestate;
+pub mod unif;
+pub mod watchpoint;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
@@ -1,26 +1,26 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod opcode;
-
-use bus::Bus;
-use cartridge::ROM;
-use cpu::Mem;
-use cpu::CPU;
+use kiko_nes::battery::BatteryFile;
+use kiko_nes::bus::Bus;
+use kiko_nes::cartridge::ROM;
+use kiko_nes::cpu::Mem;
+use kiko_nes::cpu::CPU;
+use kiko_nes::fds::{self, FdsDisk};
+use kiko_nes::joypad::JoypadButton;
+use kiko_nes::mapper::{self, Fds, Mapper};
+use kiko_nes::nsf::{self, NsfFile, NsfPlayer};
+use kiko_nes::patch;
+use kiko_nes::rom_info::RomInfo;
 use rand::Rng;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
+use std::path::{Path, PathBuf};
 
 extern crate sdl2;
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
+// NTSC CPU cycles per video frame, rounded up
+const CYCLES_PER_FRAME: u64 = 29781;
 
 fn color(byte: u8) -> Color {
     match byte {
@@ -40,7 +40,8 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     let mut frame_idx = 0;
     let mut update = false;
     for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
+        // Peeking keeps the screen from tripping read watchpoints
+        let color_idx = cpu.bus.peek(i).unwrap_or(0);
         let (b1, b2, b3) = color(color_idx).rgb();
         if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
             frame[frame_idx] = b1;
@@ -53,44 +54,206 @@ fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
     update
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+fn joypad_button(keycode: Keycode) -> Option<JoypadButton> {
+    match keycode {
+        Keycode::Up => Some(JoypadButton::UP),
+        Keycode::Down => Some(JoypadButton::DOWN),
+        Keycode::Left => Some(JoypadButton::LEFT),
+        Keycode::Right => Some(JoypadButton::RIGHT),
+        Keycode::Z => Some(JoypadButton::BUTTON_A),
+        Keycode::X => Some(JoypadButton::BUTTON_B),
+        Keycode::Space => Some(JoypadButton::SELECT),
+        Keycode::Return => Some(JoypadButton::START),
+        _ => None,
+    }
+}
+
+fn save_battery(cpu: &CPU, battery: &mut BatteryFile, force: bool) {
+    let data = match cpu.bus.battery_data() {
+        Some(data) => data,
+        None => return,
+    };
+    let result = if force {
+        battery.flush(data)
+    } else {
+        battery.flush_if_due(data)
+    };
+
+    if let Err(err) = result {
+        eprintln!("Failed to write {}: {}", battery.path().display(), err);
+    }
+}
+
+fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump, battery: &mut Option<BatteryFile>) {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
-            Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
             } => {
-                cpu.mem_write(0xff, 0x77);
+                if let Some(battery) = battery.as_mut() {
+                    save_battery(cpu, battery, true);
+                }
+                std::process::exit(0)
             }
+            // Flips a Disk System disk to its next side
             Event::KeyDown {
-                keycode: Some(Keycode::S),
+                keycode: Some(Keycode::F),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x73);
+                let sides = cpu.bus.mapper().disk_sides();
+                if sides > 0 {
+                    let side = cpu.bus.mapper().current_disk_side().map_or(0, |s| s + 1);
+                    cpu.bus.mapper_mut().insert_disk_side(Some(side % sides));
+                }
             }
             Event::KeyDown {
-                keycode: Some(Keycode::A),
+                keycode: Some(keycode),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x61);
+                if let Some(button) = joypad_button(keycode) {
+                    cpu.bus
+                        .joypad_mut(0)
+                        .set_button_pressed_status(button, true);
+                }
             }
-            Event::KeyDown {
-                keycode: Some(Keycode::D),
+            Event::KeyUp {
+                keycode: Some(keycode),
                 ..
             } => {
-                cpu.mem_write(0xff, 0x64);
+                if let Some(button) = joypad_button(keycode) {
+                    cpu.bus
+                        .joypad_mut(0)
+                        .set_button_pressed_status(button, false);
+                }
             }
             _ => { /* do nothing */ }
         }
     }
 }
 
+// Reports a problem with one of the user's files and quits
+fn exit_with_error(path: &Path, err: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", path.display(), err);
+    std::process::exit(1);
+}
+
+fn print_track(player: &NsfPlayer) {
+    let nsf = player.nsf();
+    let name = player.track_info().name.as_deref().unwrap_or(&nsf.title);
+    eprintln!("Track {}/{}: {}", player.track() + 1, nsf.total_songs, name);
+}
+
+// Plays an NSF tune, with Left and Right changing the track. There is no APU
+// yet, so this only runs the music code.
+fn play_nsf(rom_path: &Path, nsf: NsfFile, event_pump: &mut EventPump) {
+    let mut player = NsfPlayer::new(nsf);
+    let starting_song = player.track();
+    player
+        .start_track(starting_song)
+        .unwrap_or_else(|err| exit_with_error(rom_path, err));
+    print_track(&player);
+
+    loop {
+        for event in event_pump.poll_iter() {
+            let track = match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => return,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Left),
+                    ..
+                } => player.track().checked_sub(1),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Right),
+                    ..
+                } => Some(player.track().saturating_add(1))
+                    .filter(|&track| track < player.nsf().total_songs),
+                _ => None,
+            };
+            if let Some(track) = track {
+                player
+                    .start_track(track)
+                    .unwrap_or_else(|err| exit_with_error(rom_path, err));
+                print_track(&player);
+            }
+        }
+
+        player
+            .run_cycles(CYCLES_PER_FRAME as u32)
+            .unwrap_or_else(|err| exit_with_error(rom_path, err));
+        std::thread::sleep(std::time::Duration::from_millis(16));
+    }
+}
+
+// Reads the ROM and applies patches given with --patch in order, otherwise
+// one with the same name as the ROM. Returns the patches that were applied,
+// and exits with an error if any file can't be read or applied.
+fn load_with_patches(rom_path: &Path, args: &[String]) -> (Vec<u8>, Vec<PathBuf>) {
+    let mut bytes = std::fs::read(rom_path).unwrap_or_else(|err| exit_with_error(rom_path, err));
+
+    let mut patches: Vec<PathBuf> = args
+        .windows(2)
+        .filter(|pair| pair[0] == "--patch")
+        .map(|pair| PathBuf::from(&pair[1]))
+        .collect();
+    if patches.is_empty() {
+        patches.extend(patch::find_patch(rom_path));
+    }
+    for patch_path in &patches {
+        bytes = patch::apply_file(&bytes, patch_path)
+            .unwrap_or_else(|err| exit_with_error(rom_path, err));
+        eprintln!("{}: applied {}", rom_path.display(), patch_path.display());
+    }
+
+    (bytes, patches)
+}
+
+// kiko-nes info <rom> [--json] [--no-game-db] [--patch <path>]
+fn print_info(args: &[String]) {
+    let rom_path = match args.get(2) {
+        Some(path) => Path::new(path),
+        None => {
+            eprintln!("usage: kiko-nes info <rom> [--json]");
+            std::process::exit(2);
+        }
+    };
+
+    let (bytes, patches) = load_with_patches(rom_path, args);
+    let use_game_db = !args.iter().any(|arg| arg == "--no-game-db");
+    let rom =
+        ROM::with_game_db(&bytes, use_game_db).unwrap_or_else(|err| exit_with_error(rom_path, err));
+
+    let info = RomInfo {
+        path: rom_path,
+        rom: &rom,
+        patches: &patches,
+    };
+    if args.iter().any(|arg| arg == "--json") {
+        print!("{}", info.to_json());
+    } else {
+        print!("{}", info.to_text());
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("info") {
+        print_info(&args);
+        return;
+    }
+
+    let rom_path = match args.get(1).filter(|arg| !arg.starts_with("--")) {
+        Some(path) => Path::new(path),
+        None => {
+            eprintln!("usage: kiko-nes <rom> [--bios <path>] [--no-game-db] [--patch <path>]");
+            std::process::exit(2);
+        }
+    };
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -109,20 +272,88 @@ fn main() {
         .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
         .unwrap();
 
-    let bytes = std::fs::read("snake.nes").unwrap();
-    let rom = ROM::new(&bytes).unwrap();
+    let (bytes, _) = load_with_patches(rom_path, &args);
+
+    if nsf::is_nsf(&bytes) {
+        let nsf = NsfFile::parse(&bytes).unwrap_or_else(|err| exit_with_error(rom_path, err));
+        play_nsf(rom_path, nsf, &mut event_pump);
+        return;
+    }
+
+    let (mapper, has_battery): (Box<dyn Mapper>, bool) = if fds::is_disk_image(&bytes) {
+        // Disk System games run on the RAM adapter's BIOS, which we can't ship
+        let bios_path = args
+            .windows(2)
+            .find(|pair| pair[0] == "--bios")
+            .map(|pair| PathBuf::from(&pair[1]))
+            .unwrap_or_else(|| {
+                exit_with_error(rom_path, "Famicom Disk System images need --bios <path>")
+            });
+        let bios = std::fs::read(&bios_path).unwrap_or_else(|err| exit_with_error(&bios_path, err));
+        let disk = FdsDisk::parse(&bytes).unwrap_or_else(|err| exit_with_error(rom_path, err));
+        let fds = Fds::new(bios, disk).unwrap_or_else(|err| exit_with_error(&bios_path, err));
+        // Modified disks are written back to the .sav file
+        (Box::new(fds), true)
+    } else {
+        let use_game_db = !args.iter().any(|arg| arg == "--no-game-db");
+        let rom = ROM::with_game_db(&bytes, use_game_db)
+            .unwrap_or_else(|err| exit_with_error(rom_path, err));
+        for warning in &rom.warnings {
+            eprintln!("{}: {}", rom_path.display(), warning);
+        }
+        for correction in &rom.corrections {
+            eprintln!("{}: corrected {}", rom_path.display(), correction);
+        }
+        let battery = rom.battery;
+        let mapper = mapper::from_rom(rom).unwrap_or_else(|err| exit_with_error(rom_path, err));
+        // Boards that save to an EEPROM keep it without a battery
+        let battery = battery || mapper.battery_data().is_some();
+        (mapper, battery)
+    };
+
+    let mut battery = if has_battery {
+        Some(BatteryFile::for_rom(rom_path))
+    } else {
+        None
+    };
 
     //load the game
-    let bus = Bus::new(rom);
+    let mut bus = Bus::new(mapper);
+    if let Some(file) = battery.as_mut() {
+        match file.load() {
+            Ok(Some(data)) => bus.load_battery_data(&data),
+            Ok(None) => {}
+            Err(err) => {
+                // Saving would replace the file we couldn't read
+                eprintln!(
+                    "Failed to read {}: {}. Starting with blank save RAM, which won't be saved.",
+                    file.path().display(),
+                    err
+                );
+                battery = None;
+            }
+        }
+    }
+
     let mut cpu = CPU::new(bus);
     cpu.reset();
 
-    let mut screen_state = [0 as u8; 32 * 3 * 32];
+    let mut screen_state = [0_u8; 32 * 3 * 32];
     let mut rng = rand::thread_rng();
+    let mut frame = 0;
 
     // run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        handle_user_input(cpu, &mut event_pump);
+    cpu.run_with_callback(|cpu| {
+        handle_user_input(cpu, &mut event_pump, &mut battery);
+
+        // Comparing the save data is too slow to do after every instruction
+        let current_frame = cpu.bus.cycles() / CYCLES_PER_FRAME;
+        if current_frame != frame {
+            frame = current_frame;
+            if let Some(battery) = battery.as_mut() {
+                save_battery(cpu, battery, false);
+            }
+        }
 
         cpu.mem_write(0xfe, rng.gen_range(1, 16));
 
@@ -135,4 +366,9 @@ fn main() {
         }
         ::std::thread::sleep(std::time::Duration::new(0, 70_000));
     });
+
+    // The game stopped on BRK without going through the quit handler
+    if let Some(battery) = battery.as_mut() {
+        save_battery(&cpu, battery, true);
+    }
 }
@@ -0,0 +1,122 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, bus_conflict, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_SIZE: usize = 0x2000;
+
+// Mapper 7 (AxROM): switchable 32K PRG bank, with bit 4 of the latch picking
+// which nametable every screen shows
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    bus_conflicts: bool,
+    latch: u8,
+}
+
+impl Axrom {
+    pub fn new(rom: ROM) -> Self {
+        Axrom {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            // Only AMROM has bus conflicts, which NES 2.0 marks as submapper 2
+            bus_conflicts: rom.submapper == 2,
+            latch: 0,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank = (self.latch & 0b0111) as usize;
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => {
+                self.latch = if self.bus_conflicts {
+                    bus_conflict(self.cpu_peek(addr), data)
+                } else {
+                    data
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, CHR_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, CHR_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.latch & 0b1_0000 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.latch);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.latch = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_switches_32k_bank() {
+        let mut mapper = Axrom::new(test_rom(7, 1, 4, 0x8000, 0, 0x2000));
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xffff), Some(3));
+    }
+
+    #[test]
+    fn test_selects_single_screen_nametable() {
+        let mut mapper = Axrom::new(test_rom(7, 1, 4, 0x8000, 0, 0x2000));
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+
+        mapper.cpu_write(0x8000, 0b1_0000);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_amrom_has_bus_conflicts() {
+        let mut mapper = Axrom::new(test_rom(7, 2, 4, 0x8000, 0, 0x2000));
+        mapper.cpu_write(0x8000, 0b1_0011);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
@@ -0,0 +1,421 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::eeprom::{Eeprom, EepromChip};
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Chip {
+    // FCG-1/2: registers at $6000-$7FFF, IRQ counter written directly
+    Fcg,
+    // LZ93D50: registers at $8000-$FFFF, IRQ counter loaded from a latch
+    Lz93d50,
+    // Old mapper 16 headers can't say which, so both ranges are decoded
+    Either,
+}
+
+// Mappers 16, 153, 157 and 159 (Bandai FCG boards). 16K PRG banking with the
+// last bank fixed, 1K CHR banking and a CPU cycle IRQ counter. Saves live in
+// a serial EEPROM, except on mapper 153 which has battery-backed RAM and uses
+// the CHR registers for a 256K outer PRG bank.
+pub struct BandaiFcg {
+    mapper: u16,
+    chip: Chip,
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+    eeprom: Option<Eeprom>,
+    // Datach's second EEPROM sits in the game cartridge, clocked by bit 3 of
+    // the CHR registers
+    datach_eeprom: Option<Eeprom>,
+    // Both Datach EEPROMs back to back, since saves are one file
+    datach_save: Vec<u8>,
+
+    prg_bank: u8,
+    outer_prg_bank: u8,
+    chr_banks: [u8; 8],
+    mirroring: u8,
+
+    irq_enabled: bool,
+    irq_counter: u16,
+    irq_latch: u16,
+    irq_pending: bool,
+}
+
+impl BandaiFcg {
+    pub fn new(rom: ROM) -> Self {
+        let chip = match (rom.mapper, rom.submapper) {
+            (16, 4) => Chip::Fcg,
+            (16, 5) | (153, _) | (157, _) | (159, _) => Chip::Lz93d50,
+            _ => Chip::Either,
+        };
+
+        // NES 2.0 gives the EEPROM size as NVRAM, otherwise go by mapper.
+        // Plain mapper 16 covers plenty of boards without one, so only a
+        // header that declares a save gets it.
+        let declares_save = rom.battery || rom.prg_nvram_size != 0;
+        let eeprom = match (rom.mapper, rom.prg_nvram_size) {
+            (153, _) => None,
+            (16, _) if chip == Chip::Fcg => None,
+            (16, _) if chip == Chip::Either && !declares_save => None,
+            (_, 128) | (159, 0) => Some(Eeprom::new(EepromChip::X24C01)),
+            _ => Some(Eeprom::new(EepromChip::C24C02)),
+        };
+        let datach_eeprom = if rom.mapper == 157 {
+            Some(Eeprom::new(EepromChip::X24C01))
+        } else {
+            None
+        };
+
+        let prg_ram = if rom.mapper == 153 {
+            let mut prg_ram = PrgRam::new(rom.work_ram_size().max(0x2000));
+            prg_ram.load_trainer(&rom.trainer);
+            prg_ram
+        } else {
+            PrgRam::new(0)
+        };
+
+        let mut mapper = BandaiFcg {
+            mapper: rom.mapper,
+            chip,
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            prg_ram,
+            battery: rom.battery,
+            eeprom,
+            datach_eeprom,
+            datach_save: vec![],
+            prg_bank: 0,
+            outer_prg_bank: 0,
+            chr_banks: [0; 8],
+            mirroring: 0,
+            irq_enabled: false,
+            irq_counter: 0,
+            irq_latch: 0,
+            irq_pending: false,
+        };
+        mapper.sync_datach_save();
+        mapper
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        let inner = if addr < 0xc000 {
+            self.prg_bank as usize & 0x0f
+        } else {
+            0x0f
+        };
+        (self.outer_prg_bank as usize) << 4 | inner
+    }
+
+    fn decodes(&self, addr: u16) -> bool {
+        match self.chip {
+            Chip::Fcg => (0x6000..=0x7fff).contains(&addr),
+            Chip::Lz93d50 => addr >= 0x8000,
+            Chip::Either => addr >= 0x6000,
+        }
+    }
+
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x0..=0x7 => {
+                self.chr_banks[register as usize] = data;
+                if self.mapper == 153 {
+                    self.outer_prg_bank = data & 1;
+                }
+                if register <= 3 {
+                    if let Some(eeprom) = self.datach_eeprom.as_mut() {
+                        eeprom.write_scl(data & 0b1000 != 0);
+                    }
+                }
+            }
+            0x8 => self.prg_bank = data,
+            0x9 => self.mirroring = data & 0b11,
+            0xa => {
+                self.irq_enabled = data & 1 != 0;
+                self.irq_pending = false;
+                if self.chip != Chip::Fcg {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            0xb | 0xc => {
+                let shift = if register == 0xb { 0 } else { 8 };
+                let value = (self.irq_latch & !(0xff << shift)) | (data as u16) << shift;
+                if self.chip == Chip::Fcg {
+                    self.irq_counter = value;
+                }
+                self.irq_latch = value;
+            }
+            0xd if self.mapper == 153 => self.prg_ram.set_enabled(data & 0b10_0000 != 0),
+            0xd => {
+                let scl = data & 0b10_0000 != 0;
+                let sda = data & 0b100_0000 != 0;
+                if let Some(eeprom) = self.eeprom.as_mut() {
+                    eeprom.write_lines(scl, sda);
+                }
+                if let Some(eeprom) = self.datach_eeprom.as_mut() {
+                    eeprom.write_sda(sda);
+                }
+            }
+            _ => {}
+        }
+        self.sync_datach_save();
+    }
+
+    fn sync_datach_save(&mut self) {
+        if let (Some(eeprom), Some(datach)) = (&self.eeprom, &self.datach_eeprom) {
+            self.datach_save.clear();
+            self.datach_save.extend_from_slice(eeprom.data());
+            self.datach_save.extend_from_slice(datach.data());
+        }
+    }
+
+    fn eeprom_output(&self) -> bool {
+        self.eeprom.as_ref().is_none_or(|e| e.read())
+            && self.datach_eeprom.as_ref().is_none_or(|e| e.read())
+    }
+}
+
+impl Mapper for BandaiFcg {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.mapper == 153 => self.prg_ram.read(addr),
+            // Only bit 4, the EEPROM's data line, is driven
+            0x6000..=0x7fff if self.eeprom.is_some() => Some((self.eeprom_output() as u8) << 4),
+            0x8000..=0xffff => {
+                let offset =
+                    bank_offset(self.prg_rom.len(), self.prg_bank(addr), PRG_BANK_SIZE, addr);
+                Some(self.prg_rom[offset])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if self.mapper == 153 && (0x6000..=0x7fff).contains(&addr) {
+            self.prg_ram.write(addr, data);
+        } else if self.decodes(addr) {
+            self.write_register(addr & 0x0f, data);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        if self.chr.is_ram() {
+            return self.chr.read(0, 0x2000, addr);
+        }
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        self.chr.read(bank, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, 0x2000, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if !self.irq_enabled {
+                return;
+            }
+            // Checked before the decrement, so a counter of 0 fires at once
+            if self.irq_counter == 0 {
+                self.irq_pending = true;
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        for eeprom in [&self.eeprom, &self.datach_eeprom].into_iter().flatten() {
+            eeprom.save_state(state);
+        }
+        state.write_u8(self.prg_bank);
+        state.write_u8(self.outer_prg_bank);
+        state.write_bytes(&self.chr_banks);
+        state.write_u8(self.mirroring);
+        state.write_bool(self.irq_enabled);
+        state.write_u16(self.irq_counter);
+        state.write_u16(self.irq_latch);
+        state.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        for eeprom in [&mut self.eeprom, &mut self.datach_eeprom]
+            .into_iter()
+            .flatten()
+        {
+            eeprom.load_state(state)?;
+        }
+        self.prg_bank = state.read_u8()?;
+        self.outer_prg_bank = state.read_u8()? & 1;
+        state.read_bytes_into(&mut self.chr_banks)?;
+        self.mirroring = state.read_u8()? & 0b11;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_counter = state.read_u16()?;
+        self.irq_latch = state.read_u16()?;
+        self.irq_pending = state.read_bool()?;
+        self.sync_datach_save();
+        Ok(())
+    }
+
+    // EEPROMs always keep their contents, battery or not
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.datach_eeprom.is_some() {
+            Some(&self.datach_save)
+        } else if let Some(eeprom) = &self.eeprom {
+            Some(eeprom.data())
+        } else if self.battery {
+            Some(self.prg_ram.data())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        match (self.eeprom.as_mut(), self.datach_eeprom.as_mut()) {
+            (Some(eeprom), Some(datach)) => {
+                let split = data.len().min(eeprom.chip().size());
+                eeprom.load(&data[..split]);
+                datach.load(&data[split..]);
+            }
+            (Some(eeprom), None) => eeprom.load(data),
+            _ => self.prg_ram.load(data),
+        }
+        self.sync_datach_save();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_prg_and_chr_banking() {
+        let mut mapper = BandaiFcg::new(test_rom(16, 5, 8, 0x4000, 16, 0x0400));
+        assert_eq!(mapper.cpu_read(0xc000), Some(7));
+
+        mapper.cpu_write(0x8008, 3);
+        mapper.cpu_write(0x8003, 9);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.ppu_read(0x0c00), 9);
+
+        // The LZ93D50 ignores $6000-$7FFF
+        mapper.cpu_write(0x6008, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+
+        mapper.cpu_write(0x8009, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn test_mapper_153_outer_bank_and_ram() {
+        let mut mapper = BandaiFcg::new(test_rom(153, 0, 32, 0x4000, 0, 0x2000));
+        assert_eq!(mapper.cpu_read(0xc000), Some(15));
+        mapper.cpu_write(0x8000, 1);
+        mapper.cpu_write(0x8008, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(18));
+        assert_eq!(mapper.cpu_read(0xc000), Some(31));
+
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+        mapper.cpu_write(0x800d, 0);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_irq_counts_cpu_cycles_from_latch() {
+        let mut mapper = BandaiFcg::new(test_rom(16, 5, 2, 0x4000, 8, 0x0400));
+        mapper.cpu_write(0x800b, 10);
+        mapper.cpu_write(0x800c, 0);
+        mapper.cpu_write(0x800a, 1);
+        mapper.cpu_clock(10);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(1);
+        assert!(mapper.irq_pending());
+        mapper.cpu_write(0x800a, 0);
+        assert!(!mapper.irq_pending());
+
+        // FCG-1/2 writes the counter itself
+        let mut mapper = BandaiFcg::new(test_rom(16, 4, 2, 0x4000, 8, 0x0400));
+        mapper.cpu_write(0x600a, 1);
+        mapper.cpu_write(0x600b, 2);
+        mapper.cpu_clock(3);
+        assert!(mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_mapper_16_eeprom_needs_a_declared_save() {
+        let rom = test_rom(16, 0, 2, 0x4000, 8, 0x0400);
+        assert!(BandaiFcg::new(rom).battery_data().is_none());
+        let mut rom = test_rom(16, 0, 2, 0x4000, 8, 0x0400);
+        rom.battery = true;
+        assert_eq!(
+            BandaiFcg::new(rom).battery_data().map(<[u8]>::len),
+            Some(256)
+        );
+    }
+
+    #[test]
+    fn test_eeprom_through_register_d() {
+        let mut mapper = BandaiFcg::new(test_rom(16, 5, 2, 0x4000, 8, 0x0400));
+        let lines = |mapper: &mut BandaiFcg, scl: bool, sda: bool| {
+            mapper.cpu_write(0x800d, (scl as u8) << 5 | (sda as u8) << 6);
+        };
+        let send = |mapper: &mut BandaiFcg, byte: u8| {
+            for i in (0..8).rev() {
+                let sda = (byte >> i) & 1 != 0;
+                lines(mapper, false, sda);
+                lines(mapper, true, sda);
+                lines(mapper, false, sda);
+            }
+            lines(mapper, false, true);
+            lines(mapper, true, true);
+            let ack = mapper.cpu_read(0x6000).unwrap() & 0x10 == 0;
+            lines(mapper, false, true);
+            ack
+        };
+
+        lines(&mut mapper, true, true);
+        lines(&mut mapper, true, false);
+        lines(&mut mapper, false, false);
+        assert!(send(&mut mapper, 0xa0));
+        assert!(send(&mut mapper, 0x20));
+        assert!(send(&mut mapper, 0x5a));
+        lines(&mut mapper, false, false);
+        lines(&mut mapper, true, false);
+        lines(&mut mapper, true, true);
+
+        let save = mapper.battery_data().unwrap();
+        assert_eq!(save.len(), 256);
+        assert_eq!(save[0x20], 0x5a);
+
+        let mut restored = BandaiFcg::new(test_rom(159, 0, 2, 0x4000, 8, 0x0400));
+        restored.load_battery_data(&[0x11; 128]);
+        assert_eq!(restored.battery_data().unwrap(), &[0x11; 128][..]);
+    }
+}
@@ -0,0 +1,225 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, bus_conflict, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const CHR_SIZE: usize = 0x2000;
+
+// Mapper 34 covers two unrelated boards. BNROM has a 32K PRG latch at
+// $8000-$FFFF and CHR-RAM; NINA-001 has registers in the top of its PRG-RAM
+// and switchable 4K CHR-ROM banks.
+pub struct Bnrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    prg_bank: u8,
+}
+
+impl Bnrom {
+    pub fn new(rom: ROM) -> Self {
+        Bnrom {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            mirroring: rom.mirroring,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Bnrom {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank = self.prg_bank as usize;
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => self.prg_bank = bus_conflict(self.cpu_peek(addr), data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, CHR_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, CHR_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+pub struct Nina001 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    prg_bank: u8,
+    chr_banks: [u8; 2],
+}
+
+impl Nina001 {
+    pub fn new(mut rom: ROM) -> Self {
+        // The board always has 8K of RAM, whatever the header says
+        if rom.work_ram_size() < 0x2000 {
+            rom.prg_ram_size = 0x2000 - rom.prg_nvram_size;
+        }
+
+        Nina001 {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            mirroring: rom.mirroring,
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        }
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        (self.chr_banks[(addr >> 12) as usize & 1] & 0b1111) as usize
+    }
+}
+
+impl Mapper for Nina001 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank = (self.prg_bank & 1) as usize;
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    // The registers sit on top of RAM, so the write lands in both
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x7ffd => self.prg_bank = data,
+            0x7ffe => self.chr_banks[0] = data,
+            0x7fff => self.chr_banks[1] = data,
+            _ => {}
+        }
+
+        if let 0x6000..=0x7fff = addr {
+            self.prg_ram.write(addr, data);
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(addr), CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr
+            .write(self.chr_bank(addr), CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.prg_bank);
+        state.write_u8(self.chr_banks[0]);
+        state.write_u8(self.chr_banks[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        self.chr_banks[0] = state.read_u8()?;
+        self.chr_banks[1] = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::{from_rom, test_rom};
+
+    #[test]
+    fn test_bnrom_switches_32k_bank() {
+        let mut rom = test_rom(34, 2, 4, 0x8000, 0, 0x2000);
+        rom.prg_rom.iter_mut().for_each(|b| *b |= 0b10);
+        let mut mapper = Bnrom::new(rom);
+
+        // Bank 0 reads 0b10, so only bit 1 of the write gets through
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0b10));
+    }
+
+    #[test]
+    fn test_nina001_keeps_trainer() {
+        let mut rom = test_rom(34, 1, 2, 0x8000, 4, 0x1000);
+        rom.trainer = vec![0x42; 512];
+        let mut mapper = Nina001::new(rom);
+        assert_eq!(mapper.cpu_read(0x7000), Some(0x42));
+        assert_eq!(mapper.cpu_read(0x71ff), Some(0x42));
+        assert_eq!(mapper.cpu_read(0x7200), Some(0));
+    }
+
+    #[test]
+    fn test_nina001_switches_banks_through_ram_registers() {
+        let mut mapper = Nina001::new(test_rom(34, 1, 2, 0x8000, 4, 0x1000));
+        mapper.cpu_write(0x7ffd, 1);
+        mapper.cpu_write(0x7ffe, 2);
+        mapper.cpu_write(0x7fff, 3);
+
+        assert_eq!(mapper.cpu_read(0x8000), Some(1));
+        assert_eq!(mapper.cpu_read(0x7ffe), Some(2));
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+        assert_eq!(mapper.ppu_read(0x1000), 3);
+    }
+
+    #[test]
+    fn test_mapper_34_picks_board_from_chr_size() {
+        let mut mapper = from_rom(test_rom(34, 0, 2, 0x8000, 4, 0x1000)).unwrap();
+        mapper.cpu_write(0x7ffe, 1);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+
+        let mut mapper = from_rom(test_rom(34, 0, 2, 0x8000, 1, 0x2000)).unwrap();
+        mapper.cpu_write(0x7ffe, 1);
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bus_conflict, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// Mapper 3 (CNROM): fixed PRG like NROM, switchable 8K CHR bank
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(rom: ROM) -> Self {
+        Cnrom {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            mirroring: rom.mirroring,
+            bus_conflicts: rom.submapper != 1,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => Some(self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => {
+                self.chr_bank = if self.bus_conflicts {
+                    bus_conflict(self.cpu_peek(addr), data)
+                } else {
+                    data
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank as usize, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr
+            .write(self.chr_bank as usize, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.chr_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_switches_chr_bank() {
+        let mut mapper = Cnrom::new(test_rom(3, 1, 1, 0x4000, 4, 0x2000));
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), 3);
+        assert_eq!(mapper.ppu_read(0x1fff), 3);
+    }
+
+    #[test]
+    fn test_16k_prg_is_mirrored() {
+        let mut mapper = Cnrom::new(test_rom(3, 1, 1, 0x4000, 1, 0x2000));
+        assert_eq!(mapper.cpu_read(0x8000), mapper.cpu_read(0xc000));
+    }
+
+    #[test]
+    fn test_bus_conflict_ands_with_rom() {
+        // $C000 is in the second 16K, which is filled with 1
+        let mut mapper = Cnrom::new(test_rom(3, 2, 2, 0x4000, 4, 0x2000));
+        mapper.cpu_write(0xc000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+    }
+}
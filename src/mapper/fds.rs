@@ -0,0 +1,683 @@
+use crate::cartridge::Mirroring;
+use crate::chr_mem::ChrMem;
+use crate::fds::{self, FdsDisk};
+use crate::mapper::Mapper;
+use crate::savestate::{StateReader, StateWriter};
+
+const BIOS_SIZE: usize = 0x2000;
+const PRG_RAM_SIZE: usize = 0x8000;
+const CHR_SIZE: usize = 0x2000;
+
+// The drive moves one byte past the head every 150 CPU cycles, about
+// 96.4 kbit/s, after taking a while to get the motor up to speed
+const BYTE_CYCLES: u16 = 150;
+const SPIN_UP_CYCLES: u16 = 50000;
+// How long a switched disk stays out, so the BIOS notices it was ejected
+const SIDE_SWITCH_CYCLES: u32 = 1_789_773;
+
+// Famicom Disk System RAM adapter: 32K of PRG-RAM at $6000-$DFFF, the BIOS at
+// $E000, 8K of CHR-RAM and the disk drive's serial interface
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: ChrMem,
+    disk: FdsDisk,
+    // Each side as the stream under the head, which games read and write
+    raw_sides: Vec<Vec<u8>>,
+    // The disk in its file format, rebuilt after the game writes to it
+    image: Vec<u8>,
+    modified: bool,
+    raw_dirty: bool,
+
+    side: Option<usize>,
+    pending_side: Option<usize>,
+    insert_delay: u32,
+
+    disk_io_enabled: bool,
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_repeat: bool,
+    irq_enabled: bool,
+    timer_irq: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    crc_control: bool,
+    transfer_enabled: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    transfer_complete: bool,
+    read_data: u8,
+    write_data: u8,
+    ext_output: u8,
+
+    position: usize,
+    delay: u16,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc: u16,
+}
+
+impl Fds {
+    pub fn new(bios: Vec<u8>, disk: FdsDisk) -> Result<Self, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!(
+                "FDS BIOS must be {} bytes, found {}",
+                BIOS_SIZE,
+                bios.len()
+            ));
+        }
+
+        let raw_sides = disk
+            .sides
+            .iter()
+            .map(|side| fds::to_raw_side(side))
+            .collect();
+        let image = disk.to_bytes();
+        Ok(Fds {
+            bios,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: ChrMem::new(vec![], CHR_SIZE),
+            disk,
+            raw_sides,
+            image,
+            modified: false,
+            raw_dirty: false,
+            side: Some(0),
+            pending_side: None,
+            insert_delay: 0,
+            disk_io_enabled: false,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_repeat: false,
+            irq_enabled: false,
+            timer_irq: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            horizontal_mirroring: false,
+            crc_control: false,
+            transfer_enabled: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            transfer_complete: false,
+            read_data: 0,
+            write_data: 0,
+            ext_output: 0,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            previous_crc_control: false,
+            crc: 0,
+        })
+    }
+
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030 => {
+                let crc_error = self.read_mode && self.crc_control && self.crc != 0;
+                let data = self.timer_irq as u8
+                    | (self.transfer_complete as u8) << 1
+                    | (crc_error as u8) << 4
+                    | (self.end_of_head as u8) << 6;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                self.transfer_complete = false;
+                Some(data)
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            _ => self.peek_register(addr),
+        }
+    }
+
+    fn peek_register(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4031 => Some(self.read_data),
+            0x4032 => {
+                let inserted = self.side.is_some();
+                let not_ready = !inserted || !self.scanning;
+                // Without a disk the drive also reports write protection
+                Some(!inserted as u8 | (not_ready as u8) << 1 | (!inserted as u8) << 2)
+            }
+            // Bit 7 is the battery check, which always passes
+            0x4033 => Some(0x80 | (self.ext_output & 0x7f)),
+            _ => None,
+        }
+    }
+
+    fn write_control(&mut self, data: u8) {
+        let was_writing = !self.read_mode;
+
+        self.motor_on = data & 0b0000_0001 != 0;
+        self.reset_transfer = data & 0b0000_0010 != 0;
+        self.read_mode = data & 0b0000_0100 != 0;
+        self.horizontal_mirroring = data & 0b0000_1000 != 0;
+        self.crc_control = data & 0b0001_0000 != 0;
+        self.transfer_enabled = data & 0b0100_0000 != 0;
+        self.disk_irq_enabled = data & 0b1000_0000 != 0;
+        self.disk_irq = false;
+
+        // Games finish a write by going back to read mode or stopping the
+        // motor, which is when the image file is brought up to date
+        if (was_writing && self.read_mode) || !self.motor_on {
+            self.sync_image();
+        }
+    }
+
+    fn sync_image(&mut self) {
+        if !self.raw_dirty {
+            return;
+        }
+
+        for (side, raw) in self.disk.sides.iter_mut().zip(&self.raw_sides) {
+            *side = fds::from_raw_side(raw);
+        }
+        // Rewriting a block with the same bytes is not a change
+        let image = self.disk.to_bytes();
+        if image != self.image {
+            self.image = image;
+            self.modified = true;
+        }
+        self.raw_dirty = false;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn clock_disk(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+
+        if self.end_of_head {
+            self.delay = SPIN_UP_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        if self.read_mode {
+            self.read_byte(side);
+        } else {
+            self.write_byte(side);
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= self.raw_sides[side].len() {
+            self.end_of_head = true;
+            self.scanning = false;
+            self.sync_image();
+        } else {
+            self.delay = BYTE_CYCLES - 1;
+        }
+    }
+
+    fn read_byte(&mut self, side: usize) {
+        let data = self.raw_sides[side][self.position];
+
+        if !self.transfer_enabled {
+            self.gap_ended = false;
+            self.crc = 0;
+            return;
+        }
+
+        self.crc = fds::update_crc(self.crc, data);
+        // The $80 start mark ends the gap and isn't passed on
+        if !self.gap_ended {
+            self.gap_ended = data != 0;
+            return;
+        }
+
+        self.read_data = data;
+        self.transfer_complete = true;
+        if self.disk_irq_enabled {
+            self.disk_irq = true;
+        }
+    }
+
+    fn write_byte(&mut self, side: usize) {
+        let data = if !self.transfer_enabled {
+            self.crc = 0;
+            0
+        } else if !self.crc_control {
+            self.crc = fds::update_crc(self.crc, self.write_data);
+            self.write_data
+        } else {
+            // Run the CRC over two zero bytes to finish it, then shift it
+            // out low byte first
+            if !self.previous_crc_control {
+                self.crc = fds::update_crc(fds::update_crc(self.crc, 0), 0);
+            }
+            let data = self.crc as u8;
+            self.crc >>= 8;
+            data
+        };
+
+        if !self.crc_control {
+            self.transfer_complete = true;
+            if self.disk_irq_enabled {
+                self.disk_irq = true;
+            }
+        }
+
+        self.raw_sides[side][self.position] = data;
+        self.raw_dirty = true;
+        self.gap_ended = false;
+    }
+}
+
+impl Mapper for Fds {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030..=0x4031 => self.read_register(addr),
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4030..=0x4033 => self.peek_register(addr),
+            0x6000..=0xdfff => Some(self.prg_ram[addr as usize - 0x6000]),
+            0xe000..=0xffff => Some(self.bios[addr as usize - 0xe000]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xff00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00ff) | (data as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = data & 0b01 != 0;
+                self.irq_enabled = data & 0b10 != 0 && self.disk_io_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = data & 0b01 != 0;
+                if !self.disk_io_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024..=0x4026 if !self.disk_io_enabled => {}
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => self.write_control(data),
+            0x4026 => self.ext_output = data,
+            0x6000..=0xdfff => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, CHR_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, CHR_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn cpu_clock(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+
+            if self.pending_side.is_some() {
+                self.insert_delay = self.insert_delay.saturating_sub(1);
+                if self.insert_delay == 0 {
+                    self.side = self.pending_side.take();
+                }
+            }
+
+            self.clock_disk();
+        }
+    }
+
+    fn disk_sides(&self) -> usize {
+        self.raw_sides.len()
+    }
+
+    fn current_disk_side(&self) -> Option<usize> {
+        self.side
+    }
+
+    fn insert_disk_side(&mut self, side: Option<usize>) {
+        self.sync_image();
+        self.side = None;
+        self.pending_side = side.filter(|&side| side < self.raw_sides.len());
+        self.insert_delay = SIDE_SWITCH_CYCLES;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.prg_ram);
+        self.chr.save_state(state);
+        for raw in &self.raw_sides {
+            state.write_bytes(raw);
+        }
+        state.write_u8(self.side.map_or(0xff, |side| side as u8));
+        state.write_u8(self.pending_side.map_or(0xff, |side| side as u8));
+        state.write_u32(self.insert_delay);
+
+        state.write_bool(self.disk_io_enabled);
+        state.write_u16(self.irq_reload);
+        state.write_u16(self.irq_counter);
+        state.write_bool(self.irq_repeat);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.timer_irq);
+
+        state.write_bool(self.motor_on);
+        state.write_bool(self.reset_transfer);
+        state.write_bool(self.read_mode);
+        state.write_bool(self.horizontal_mirroring);
+        state.write_bool(self.crc_control);
+        state.write_bool(self.transfer_enabled);
+        state.write_bool(self.disk_irq_enabled);
+        state.write_bool(self.disk_irq);
+        state.write_bool(self.transfer_complete);
+        state.write_u8(self.read_data);
+        state.write_u8(self.write_data);
+        state.write_u8(self.ext_output);
+
+        state.write_u32(self.position as u32);
+        state.write_u16(self.delay);
+        state.write_bool(self.end_of_head);
+        state.write_bool(self.scanning);
+        state.write_bool(self.gap_ended);
+        state.write_bool(self.previous_crc_control);
+        state.write_u16(self.crc);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes_into(&mut self.prg_ram)?;
+        self.chr.load_state(state)?;
+        for raw in self.raw_sides.iter_mut() {
+            state.read_bytes_into(raw)?;
+        }
+        let sides = self.raw_sides.len();
+        let read_side = |value: u8| Some(value as usize).filter(|&side| side < sides);
+        self.side = read_side(state.read_u8()?);
+        self.pending_side = read_side(state.read_u8()?);
+        self.insert_delay = state.read_u32()?;
+
+        self.disk_io_enabled = state.read_bool()?;
+        self.irq_reload = state.read_u16()?;
+        self.irq_counter = state.read_u16()?;
+        self.irq_repeat = state.read_bool()?;
+        self.irq_enabled = state.read_bool()?;
+        self.timer_irq = state.read_bool()?;
+
+        self.motor_on = state.read_bool()?;
+        self.reset_transfer = state.read_bool()?;
+        self.read_mode = state.read_bool()?;
+        self.horizontal_mirroring = state.read_bool()?;
+        self.crc_control = state.read_bool()?;
+        self.transfer_enabled = state.read_bool()?;
+        self.disk_irq_enabled = state.read_bool()?;
+        self.disk_irq = state.read_bool()?;
+        self.transfer_complete = state.read_bool()?;
+        self.read_data = state.read_u8()?;
+        self.write_data = state.read_u8()?;
+        self.ext_output = state.read_u8()?;
+
+        self.position = (state.read_u32()? as usize).min(fds::RAW_SIDE_SIZE - 1);
+        self.delay = state.read_u16()?;
+        self.end_of_head = state.read_bool()?;
+        self.scanning = state.read_bool()?;
+        self.gap_ended = state.read_bool()?;
+        self.previous_crc_control = state.read_bool()?;
+        self.crc = state.read_u16()?;
+
+        // The disk contents may differ from the image on file now
+        self.raw_dirty = true;
+        self.sync_image();
+        Ok(())
+    }
+
+    // The disk is written back like battery RAM, once the game has changed it
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.modified {
+            Some(&self.image)
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        let disk = match FdsDisk::parse(data) {
+            Ok(disk) if disk.sides.len() == self.raw_sides.len() => disk,
+            _ => return,
+        };
+
+        self.raw_sides = disk
+            .sides
+            .iter()
+            .map(|side| fds::to_raw_side(side))
+            .collect();
+        self.disk = disk;
+        self.image = self.disk.to_bytes();
+        self.modified = true;
+        self.raw_dirty = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fds::{test_side, LEAD_IN_GAP};
+
+    fn stub_bios() -> Vec<u8> {
+        let mut bios = vec![0xea; BIOS_SIZE];
+        // Reset vector to $E000
+        bios[0x1ffc] = 0x00;
+        bios[0x1ffd] = 0xe0;
+        bios
+    }
+
+    fn test_fds(sides: usize) -> Fds {
+        let disk = FdsDisk::parse(
+            &(0..sides)
+                .flat_map(|_| test_side(&[b"hello"]))
+                .collect::<Vec<u8>>(),
+        )
+        .unwrap();
+        Fds::new(stub_bios(), disk).unwrap()
+    }
+
+    fn clock(fds: &mut Fds, cycles: u32) {
+        for _ in 0..cycles {
+            fds.cpu_clock(1);
+        }
+    }
+
+    // Runs the drive until it has passed a byte to or from the CPU, which in
+    // read mode means getting through the gap first
+    fn next_byte(fds: &mut Fds) {
+        let limit = SPIN_UP_CYCLES as usize + (LEAD_IN_GAP + 2) * BYTE_CYCLES as usize;
+        for _ in 0..limit {
+            fds.cpu_clock(1);
+            if fds.cpu_read(0x4030).unwrap() & 0b10 != 0 {
+                return;
+            }
+        }
+        panic!("no byte transferred");
+    }
+
+    #[test]
+    fn test_memory_map_and_timer_irq() {
+        let mut fds = test_fds(1);
+        fds.cpu_write(0x6000, 0x12);
+        fds.cpu_write(0xdfff, 0x34);
+        assert_eq!(fds.cpu_read(0x6000), Some(0x12));
+        assert_eq!(fds.cpu_read(0xdfff), Some(0x34));
+        assert_eq!(fds.cpu_read(0xfffd), Some(0xe0));
+        fds.cpu_write(0xe000, 0);
+        assert_eq!(fds.cpu_read(0xe000), Some(0xea));
+
+        // The timer only runs with disk I/O enabled
+        fds.cpu_write(0x4020, 10);
+        fds.cpu_write(0x4021, 0);
+        fds.cpu_write(0x4022, 0b10);
+        clock(&mut fds, 20);
+        assert!(!fds.irq_pending());
+
+        fds.cpu_write(0x4023, 1);
+        fds.cpu_write(0x4022, 0b11);
+        clock(&mut fds, 10);
+        assert!(!fds.irq_pending());
+        clock(&mut fds, 1);
+        assert!(fds.irq_pending());
+        assert_eq!(fds.cpu_read(0x4030).unwrap() & 1, 1);
+        assert!(!fds.irq_pending());
+
+        // Repeat reloads the counter
+        clock(&mut fds, 11);
+        assert!(fds.irq_pending());
+    }
+
+    #[test]
+    fn test_reads_first_block_from_disk() {
+        let mut fds = test_fds(1);
+        fds.cpu_write(0x4023, 1);
+        assert_eq!(fds.cpu_read(0x4032).unwrap() & 0b11, 0b10);
+
+        // Motor on, read mode, transfer and IRQs enabled
+        fds.cpu_write(0x4025, 0b1100_0101);
+        let mut data = vec![];
+        for _ in 0..15 {
+            next_byte(&mut fds);
+            data.push(fds.cpu_read(0x4031).unwrap());
+        }
+        assert_eq!(data, b"\x01*NINTENDO-HVC*");
+        assert_eq!(fds.cpu_read(0x4032).unwrap() & 0b11, 0);
+    }
+
+    #[test]
+    fn test_writes_block_back_to_image() {
+        let mut fds = test_fds(1);
+        assert!(fds.battery_data().is_none());
+
+        let mut info = b"\x01*NINTENDO-HVC*".to_vec();
+        info.resize(56, 0x5a);
+
+        fds.cpu_write(0x4023, 1);
+        fds.cpu_write(0x4024, 0);
+        // Motor on, write mode, gap
+        fds.cpu_write(0x4025, 0b0000_0001);
+        for _ in 0..LEAD_IN_GAP {
+            next_byte(&mut fds);
+        }
+        fds.cpu_write(0x4025, 0b0100_0001);
+        for &byte in [0x80].iter().chain(&info) {
+            fds.cpu_write(0x4024, byte);
+            next_byte(&mut fds);
+        }
+        fds.cpu_write(0x4025, 0b0101_0001);
+        clock(&mut fds, BYTE_CYCLES as u32 * 2);
+
+        let crc_pos = LEAD_IN_GAP + 1 + info.len();
+        assert_eq!(
+            fds.raw_sides[0][crc_pos..crc_pos + 2],
+            fds::crc(&info).to_le_bytes()
+        );
+
+        // Back to read mode
+        fds.cpu_write(0x4025, 0b0000_0101);
+        let disk = FdsDisk::parse(fds.battery_data().unwrap()).unwrap();
+        let mut expected = test_side(&[b"hello"]);
+        expected[..56].copy_from_slice(&info);
+        assert_eq!(disk.sides, vec![expected]);
+    }
+
+    #[test]
+    fn test_loading_state_of_unchanged_disk_is_not_a_write() {
+        let mut fds = test_fds(1);
+        let mut state = StateWriter::new();
+        fds.save_state(&mut state);
+        let state = state.finish();
+
+        fds.load_state(&mut StateReader::new(&state)).unwrap();
+        assert!(fds.battery_data().is_none());
+
+        fds.raw_sides[0][LEAD_IN_GAP + 20] ^= 0xff;
+        let mut state = StateWriter::new();
+        fds.save_state(&mut state);
+        let state = state.finish();
+
+        let mut fds = test_fds(1);
+        fds.load_state(&mut StateReader::new(&state)).unwrap();
+        assert!(fds.battery_data().is_some());
+    }
+
+    #[test]
+    fn test_switching_sides_ejects_first() {
+        let mut fds = test_fds(2);
+        assert_eq!(fds.disk_sides(), 2);
+
+        fds.insert_disk_side(Some(1));
+        assert_eq!(fds.current_disk_side(), None);
+        assert_eq!(fds.cpu_read(0x4032).unwrap() & 1, 1);
+
+        clock(&mut fds, SIDE_SWITCH_CYCLES);
+        assert_eq!(fds.current_disk_side(), Some(1));
+        assert_eq!(fds.cpu_read(0x4032).unwrap() & 1, 0);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// Mapper 69 (Sunsoft FME-7). A command written to $8000 picks which of 16
+// registers the next $A000 write lands in: eight 1K CHR banks, four 8K PRG
+// banks with the first at $6000 able to show PRG-RAM instead, mirroring and a
+// 16-bit IRQ counter that ticks down every CPU cycle.
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000 bank: bit 7 enables RAM, bit 6 picks RAM over ROM
+    prg_ram_bank: u8,
+    prg_banks: [u8; 3],
+    mirroring: u8,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+}
+
+impl Fme7 {
+    pub fn new(rom: ROM) -> Self {
+        Fme7 {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            battery: rom.battery,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_ram_bank: 0,
+            prg_banks: [0; 3],
+            mirroring: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+        }
+    }
+
+    fn ram_selected(&self) -> bool {
+        self.prg_ram_bank & 0b0100_0000 != 0
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => {
+                self.prg_ram_bank = data;
+                self.prg_ram.set_enabled(data & 0b1000_0000 != 0);
+                self.prg_ram.set_bank((data & 0b11_1111) as usize);
+            }
+            0x9..=0xb => self.prg_banks[self.command as usize - 0x9] = data & 0b11_1111,
+            0xc => self.mirroring = data & 0b11,
+            0xd => {
+                self.irq_enabled = data & 0b1 != 0;
+                self.irq_counter_enabled = data & 0b1000_0000 != 0;
+                self.irq_pending = false;
+            }
+            0xe => self.irq_counter = (self.irq_counter & 0xff00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00ff) | (data as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        let bank = match addr {
+            0x6000..=0x7fff if self.ram_selected() => return self.prg_ram.read(addr),
+            0x6000..=0x7fff => (self.prg_ram_bank & 0b11_1111) as usize,
+            0x8000..=0xdfff => self.prg_banks[(addr as usize - 0x8000) / PRG_BANK_SIZE] as usize,
+            0xe000..=0xffff => (self.prg_rom.len() / PRG_BANK_SIZE).wrapping_sub(1),
+            _ => return None,
+        };
+
+        Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.ram_selected() => self.prg_ram.write(addr, data),
+            0x8000..=0x9fff => self.command = data & 0x0f,
+            0xa000..=0xbfff => self.write_parameter(data),
+            // $C000-$FFFF is the Sunsoft 5B's audio, which we don't emulate
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        self.chr.read(bank, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        self.chr.write(bank, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self, cycles: u8) {
+        if !self.irq_counter_enabled {
+            return;
+        }
+
+        for _ in 0..cycles {
+            // Fires when the counter wraps from $0000 to $FFFF
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xffff && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.command);
+        state.write_bytes(&self.chr_banks);
+        state.write_u8(self.prg_ram_bank);
+        state.write_bytes(&self.prg_banks);
+        state.write_u8(self.mirroring);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_counter_enabled);
+        state.write_u16(self.irq_counter);
+        state.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.command = state.read_u8()? & 0x0f;
+        state.read_bytes_into(&mut self.chr_banks)?;
+        self.prg_ram_bank = state.read_u8()?;
+        state.read_bytes_into(&mut self.prg_banks)?;
+        self.mirroring = state.read_u8()? & 0b11;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_counter_enabled = state.read_bool()?;
+        self.irq_counter = state.read_u16()?;
+        self.irq_pending = state.read_bool()?;
+        Ok(())
+    }
+
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(self.prg_ram.data())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    fn write_register(mapper: &mut Fme7, command: u8, data: u8) {
+        mapper.cpu_write(0x8000, command);
+        mapper.cpu_write(0xa000, data);
+    }
+
+    #[test]
+    fn test_banks_and_6000_rom_or_ram() {
+        let mut rom = test_rom(69, 0, 16, 0x2000, 16, 0x0400);
+        rom.prg_ram_size = 0x2000;
+        let mut mapper = Fme7::new(rom);
+        assert_eq!(mapper.cpu_read(0xe000), Some(15));
+
+        write_register(&mut mapper, 0x9, 3);
+        write_register(&mut mapper, 0xb, 5);
+        write_register(&mut mapper, 0x6, 9);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xc000), Some(5));
+        assert_eq!(mapper.ppu_read(0x1800), 9);
+
+        write_register(&mut mapper, 0x8, 7);
+        assert_eq!(mapper.cpu_read(0x6000), Some(7));
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), Some(7));
+
+        // RAM selected but not enabled leaves the bus open
+        write_register(&mut mapper, 0x8, 0b0100_0000);
+        assert_eq!(mapper.cpu_read(0x6000), None);
+        write_register(&mut mapper, 0x8, 0b1100_0000);
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_irq_fires_when_counter_wraps() {
+        let mut mapper = Fme7::new(test_rom(69, 0, 4, 0x2000, 8, 0x0400));
+        write_register(&mut mapper, 0xe, 4);
+        write_register(&mut mapper, 0xf, 0);
+        write_register(&mut mapper, 0xd, 0b1000_0001);
+        mapper.cpu_clock(4);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(1);
+        assert!(mapper.irq_pending());
+
+        write_register(&mut mapper, 0xd, 0b1000_0000);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(0xff);
+        assert!(!mapper.irq_pending());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, bus_conflict, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+// Mapper 66 (GxROM/MxROM): one latch selecting a 32K PRG bank with bits 4-5
+// and an 8K CHR bank with bits 0-1
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    latch: u8,
+}
+
+impl Gxrom {
+    pub fn new(rom: ROM) -> Self {
+        Gxrom {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            mirroring: rom.mirroring,
+            latch: 0,
+        }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank = ((self.latch >> 4) & 0b11) as usize;
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => self.latch = bus_conflict(self.cpu_peek(addr), data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr
+            .read((self.latch & 0b11) as usize, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr
+            .write((self.latch & 0b11) as usize, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.latch);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.latch = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_switches_prg_and_chr_banks() {
+        // Every ROM byte has the latch bits set, so nothing is lost to bus
+        // conflicts
+        let mut rom = test_rom(66, 0, 8, 0x4000, 4, 0x2000);
+        rom.prg_rom.iter_mut().for_each(|b| *b |= 0b11_0011);
+        let mut mapper = Gxrom::new(rom);
+
+        mapper.cpu_write(0x8000, 0b10_0001);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0b11_0011 | 0b100));
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+    }
+
+    #[test]
+    fn test_bus_conflict_ands_with_rom() {
+        let mut rom = test_rom(66, 0, 8, 0x4000, 4, 0x2000);
+        rom.prg_rom.iter_mut().for_each(|b| *b |= 0b01_0010);
+        let mut mapper = Gxrom::new(rom);
+
+        mapper.cpu_write(0x8000, 0b11_0011);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0b01_0010 | 0b10));
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+    }
+}
@@ -0,0 +1,362 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+// SUROM/SXROM reach 512K PRG through a CHR register bit
+const PRG_OUTER_BANK_SIZE: usize = 0x40000;
+
+// Mapper 1 (SxROM). Registers are loaded one bit at a time through a 5-bit
+// shift register at $8000-$FFFF; the fifth write picks the register by
+// address bits 13-14.
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+
+    shift_register: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    // Cycle counter used to spot writes on back-to-back cycles
+    cycles: u64,
+    last_write_cycle: Option<u64>,
+}
+
+impl Mmc1 {
+    pub fn new(rom: ROM) -> Self {
+        Mmc1 {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            battery: rom.battery,
+            shift_register: 0,
+            shift_count: 0,
+            // Power on with the last PRG bank fixed at $C000
+            control: 0b0_11_00,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycles: 0,
+            last_write_cycle: None,
+        }
+    }
+
+    fn prg_mode(&self) -> u8 {
+        (self.control >> 2) & 0b11
+    }
+
+    fn chr_4k_mode(&self) -> bool {
+        self.control & 0b1_00_00 != 0
+    }
+
+    // On 512K boards CHR bank bit 4 picks which 256K half the PRG registers
+    // see. CHR-RAM is only 8K there so the bit is free.
+    fn prg_outer_bank(&self) -> usize {
+        if self.prg_rom.len() > PRG_OUTER_BANK_SIZE {
+            ((self.chr_bank_0 >> 4) & 1) as usize * (PRG_OUTER_BANK_SIZE / PRG_BANK_SIZE)
+        } else {
+            0
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let outer = self.prg_outer_bank();
+        let bank = (self.prg_bank & 0b1111) as usize;
+        let last = PRG_OUTER_BANK_SIZE / PRG_BANK_SIZE - 1;
+        let upper = addr >= 0xc000;
+
+        let bank = match (self.prg_mode(), upper) {
+            // 32K mode ignores the low bit of the bank number
+            (0, false) | (1, false) => bank & !1,
+            (0, true) | (1, true) => bank | 1,
+            (2, false) => 0,
+            (2, true) => bank,
+            (_, false) => bank,
+            (_, true) => last,
+        };
+
+        bank_offset(self.prg_rom.len(), outer + bank, PRG_BANK_SIZE, addr)
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        let bank = if self.chr_4k_mode() {
+            if addr < 0x1000 {
+                self.chr_bank_0
+            } else {
+                self.chr_bank_1
+            }
+        } else {
+            // 8K mode ignores the low bit
+            (self.chr_bank_0 & !1) | ((addr >> 12) as u8 & 1)
+        };
+
+        bank as usize
+    }
+
+    // SOROM has 16K of PRG-RAM banked by CHR bit 3, SXROM 32K banked by
+    // bits 2-3
+    fn update_prg_ram_bank(&mut self) {
+        let bank = match self.prg_ram.bank_count() {
+            2 => (self.chr_bank_0 >> 3) & 1,
+            4 => (self.chr_bank_0 >> 2) & 0b11,
+            _ => 0,
+        };
+        self.prg_ram.set_bank(bank as usize);
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9fff => self.control = data,
+            0xa000..=0xbfff => self.chr_bank_0 = data,
+            0xc000..=0xdfff => self.chr_bank_1 = data,
+            _ => {
+                self.prg_bank = data;
+                self.prg_ram.set_enabled(data & 0b1_0000 == 0);
+            }
+        }
+
+        self.update_prg_ram_bank();
+    }
+
+    fn write_shift_register(&mut self, addr: u16, data: u8) {
+        // The serial port ignores a write on the cycle right after another,
+        // which happens with the dummy write of read-modify-write instructions
+        let consecutive = self.last_write_cycle == Some(self.cycles);
+        self.last_write_cycle = Some(self.cycles);
+        if consecutive {
+            return;
+        }
+
+        if data & 0b1000_0000 != 0 {
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.control |= 0b0_11_00;
+            return;
+        }
+
+        self.shift_register |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+
+        if self.shift_count == 5 {
+            let value = self.shift_register;
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.write_register(addr, value);
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff if !self.prg_rom.is_empty() => {
+                Some(self.prg_rom[self.prg_offset(addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => self.write_shift_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(addr), CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr
+            .write(self.chr_bank(addr), CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    // Writes are only ever compared within one instruction, so the count
+    // doesn't need to survive a save state
+    fn cpu_clock(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.shift_register);
+        state.write_u8(self.shift_count);
+        state.write_u8(self.control);
+        state.write_u8(self.chr_bank_0);
+        state.write_u8(self.chr_bank_1);
+        state.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.shift_register = state.read_u8()?;
+        self.shift_count = state.read_u8()? % 5;
+        self.control = state.read_u8()?;
+        self.chr_bank_0 = state.read_u8()?;
+        self.chr_bank_1 = state.read_u8()?;
+        self.prg_bank = state.read_u8()?;
+        Ok(())
+    }
+
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(self.prg_ram.data())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::{Mem, CPU};
+    use crate::mapper::test_rom;
+
+    // Loads a register one bit per instruction, low bit first
+    fn write_serial(mapper: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(addr, (value >> bit) & 1);
+            mapper.cpu_clock(4);
+        }
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = Mmc1::new(test_rom(1, 0, 8, 0x4000, 8, 0x1000));
+        write_serial(&mut mapper, 0xe000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(5));
+        assert_eq!(mapper.cpu_read(0xc000), Some(7));
+
+        write_serial(&mut mapper, 0x8000, 0b0_10_00);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xc000), Some(5));
+
+        // 32K mode ignores the low bit
+        write_serial(&mut mapper, 0x8000, 0b0_00_00);
+        assert_eq!(mapper.cpu_read(0x8000), Some(4));
+        assert_eq!(mapper.cpu_read(0xc000), Some(5));
+        write_serial(&mut mapper, 0x8000, 0b0_01_00);
+        assert_eq!(mapper.cpu_read(0x8000), Some(4));
+    }
+
+    #[test]
+    fn test_chr_modes_and_mirroring() {
+        let mut mapper = Mmc1::new(test_rom(1, 0, 8, 0x4000, 8, 0x1000));
+        write_serial(&mut mapper, 0xa000, 5);
+        write_serial(&mut mapper, 0xc000, 2);
+        assert_eq!(mapper.ppu_read(0x0000), 4);
+        assert_eq!(mapper.ppu_read(0x1000), 5);
+
+        write_serial(&mut mapper, 0x8000, 0b1_11_10);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        assert_eq!(mapper.ppu_read(0x1000), 2);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+
+        for (control, mirroring) in [
+            (0, Mirroring::SingleScreenLower),
+            (1, Mirroring::SingleScreenUpper),
+            (3, Mirroring::Horizontal),
+        ] {
+            write_serial(&mut mapper, 0x8000, 0b1_11_00 | control);
+            assert_eq!(mapper.mirroring(), mirroring);
+        }
+    }
+
+    #[test]
+    fn test_bit_7_resets_shift_register_and_fixes_last_bank() {
+        let mut mapper = Mmc1::new(test_rom(1, 0, 8, 0x4000, 8, 0x1000));
+        write_serial(&mut mapper, 0x8000, 0b0_10_00);
+        write_serial(&mut mapper, 0xe000, 3);
+        assert_eq!(mapper.cpu_read(0xc000), Some(3));
+
+        mapper.cpu_write(0xe000, 1);
+        mapper.cpu_clock(4);
+        mapper.cpu_write(0xe000, 1);
+        mapper.cpu_clock(4);
+        mapper.cpu_write(0x8000, 0x80);
+        mapper.cpu_clock(4);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xc000), Some(7));
+
+        // The next five writes start from an empty shift register
+        write_serial(&mut mapper, 0xe000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+    }
+
+    #[test]
+    fn test_surom_outer_bank_from_chr_register() {
+        let mut mapper = Mmc1::new(test_rom(1, 0, 32, 0x4000, 2, 0x1000));
+        write_serial(&mut mapper, 0xe000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xc000), Some(15));
+
+        write_serial(&mut mapper, 0xa000, 0b1_0000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(18));
+        assert_eq!(mapper.cpu_read(0xc000), Some(31));
+    }
+
+    #[test]
+    fn test_consecutive_writes_are_ignored() {
+        let mut mapper = Mmc1::new(test_rom(1, 0, 16, 0x4000, 8, 0x1000));
+        // Five writes, but the second lands on the same cycle as the first
+        mapper.cpu_write(0xe000, 1);
+        for _ in 0..4 {
+            mapper.cpu_write(0xe000, 1);
+            mapper.cpu_clock(4);
+        }
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        mapper.cpu_write(0xe000, 0);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0b01111));
+    }
+
+    #[test]
+    fn test_read_modify_write_only_shifts_once() {
+        let rom = test_rom(1, 0, 16, 0x4000, 8, 0x1000);
+        let mut cpu = CPU::new(Bus::new(Box::new(Mmc1::new(rom))));
+        // INC $8000 reads 0 from bank 0, writes the 0 back and then a 1 on
+        // the next cycle. Only the first write reaches the shift register.
+        for (i, byte) in [0xee, 0x00, 0x80, 0x00].iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        cpu.program_counter = 0x0600;
+        cpu.run();
+
+        for bit in [1, 1, 0, 0] {
+            cpu.mem_write(0xe000, bit);
+            cpu.bus.tick(4);
+        }
+        assert_eq!(cpu.mem_read(0x8000), 0b00110);
+    }
+}
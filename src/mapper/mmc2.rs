@@ -0,0 +1,213 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// Mappers 9 (MMC2) and 10 (MMC4). Each 4K CHR half has two banks, and a
+// latch picks between them whenever the PPU fetches tile $FD or $FE from that
+// half, so games can switch graphics partway down the screen without IRQs.
+// MMC2 has an 8K PRG bank with three fixed, MMC4 a 16K bank with one.
+pub struct Mmc2 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+    mmc4: bool,
+
+    prg_bank: u8,
+    // Indexed by [half][latch], where latch 0 is $FD and 1 is $FE
+    chr_banks: [[u8; 2]; 2],
+    latches: [u8; 2],
+    horizontal_mirroring: bool,
+}
+
+impl Mmc2 {
+    pub fn new(rom: ROM) -> Self {
+        Mmc2 {
+            prg_ram: PrgRam::for_rom(&rom),
+            mmc4: rom.mapper == 10,
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            battery: rom.battery,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [1, 1],
+            horizontal_mirroring: false,
+        }
+    }
+
+    fn prg_bank_size(&self) -> usize {
+        if self.mmc4 {
+            0x4000
+        } else {
+            0x2000
+        }
+    }
+
+    // Fetches from $xFD8-$xFDF or $xFE8-$xFEF set the latch for that half,
+    // except that MMC2's low half only reacts to $0FD8 and $0FE8 themselves
+    fn update_latch(&mut self, addr: u16) {
+        let half = (addr >> 12) as usize & 1;
+        let tile_addr = addr & 0x0ff8;
+        let latch = match tile_addr {
+            0x0fd8 => 0,
+            0x0fe8 => 1,
+            _ => return,
+        };
+        if half == 0 && !self.mmc4 && addr & 0x0007 != 0 {
+            return;
+        }
+        self.latches[half] = latch;
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank_size = self.prg_bank_size();
+                let banks = self.prg_rom.len() / bank_size;
+                let bank = if addr < 0x8000 + bank_size as u16 {
+                    self.prg_bank as usize
+                } else {
+                    // The rest of the window is the last banks in order
+                    let from_end = (0x10000 - addr as usize - 1) / bank_size;
+                    banks.wrapping_sub(1 + from_end)
+                };
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, bank_size, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0xa000..=0xafff => self.prg_bank = data & 0x0f,
+            0xb000..=0xefff => {
+                let register = (addr - 0xb000) as usize / 0x1000;
+                self.chr_banks[register / 2][register % 2] = data & 0x1f;
+            }
+            0xf000..=0xffff => self.horizontal_mirroring = data & 1 != 0,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let half = (addr >> 12) as usize & 1;
+        let bank = self.chr_banks[half][self.latches[half] as usize] as usize;
+        let data = self.chr.read(bank, CHR_BANK_SIZE, addr);
+        // The new bank takes effect after the triggering fetch
+        self.update_latch(addr);
+        data
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let half = (addr >> 12) as usize & 1;
+        let bank = self.chr_banks[half][self.latches[half] as usize] as usize;
+        self.chr.write(bank, CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.prg_bank);
+        for banks in &self.chr_banks {
+            state.write_bytes(banks);
+        }
+        state.write_bytes(&self.latches);
+        state.write_bool(self.horizontal_mirroring);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        for banks in self.chr_banks.iter_mut() {
+            state.read_bytes_into(banks)?;
+        }
+        state.read_bytes_into(&mut self.latches)?;
+        for latch in self.latches.iter_mut() {
+            *latch &= 1;
+        }
+        self.horizontal_mirroring = state.read_bool()?;
+        Ok(())
+    }
+
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(self.prg_ram.data())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_mmc2_prg_fixes_last_three_banks() {
+        let mut mapper = Mmc2::new(test_rom(9, 0, 16, 0x2000, 32, 0x1000));
+        mapper.cpu_write(0xa000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(5));
+        assert_eq!(mapper.cpu_read(0xa000), Some(13));
+        assert_eq!(mapper.cpu_read(0xc000), Some(14));
+        assert_eq!(mapper.cpu_read(0xffff), Some(15));
+
+        let mut mapper = Mmc2::new(test_rom(10, 0, 8, 0x4000, 32, 0x1000));
+        mapper.cpu_write(0xa000, 3);
+        assert_eq!(mapper.cpu_read(0xbfff), Some(3));
+        assert_eq!(mapper.cpu_read(0xc000), Some(7));
+    }
+
+    #[test]
+    fn test_tile_fetches_flip_chr_latches() {
+        let mut mapper = Mmc2::new(test_rom(9, 0, 16, 0x2000, 32, 0x1000));
+        mapper.cpu_write(0xb000, 1);
+        mapper.cpu_write(0xc000, 2);
+        mapper.cpu_write(0xd000, 3);
+        mapper.cpu_write(0xe000, 4);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+        assert_eq!(mapper.ppu_read(0x1000), 4);
+
+        // The fetch that trips the latch still comes from the old bank
+        assert_eq!(mapper.ppu_read(0x0fd8), 2);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+        assert_eq!(mapper.ppu_read(0x1fdf), 4);
+        assert_eq!(mapper.ppu_read(0x1000), 3);
+
+        // MMC2 only watches $0FE8 itself in the low half
+        mapper.ppu_read(0x0fe9);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+        mapper.ppu_read(0x0fe8);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+
+        let mut mapper = Mmc2::new(test_rom(10, 0, 8, 0x4000, 32, 0x1000));
+        mapper.cpu_write(0xb000, 1);
+        mapper.ppu_read(0x0fdd);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+    }
+}
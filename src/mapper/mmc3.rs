@@ -0,0 +1,513 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const MMC6_RAM_SIZE: usize = 0x0400;
+const TQROM_CHR_RAM_SIZE: usize = 0x2000;
+// A12 has to stay low this many PPU fetches before a rise counts, which
+// filters out the short drops during sprite fetches
+const A12_LOW_FETCHES: u8 = 3;
+
+// When the scanline counter reaches zero. Sharp MMC3B/C chips raise an IRQ
+// every time the counter is zero after a clock, so a latch of 0 fires every
+// scanline; NEC MMC3A chips only fire when it decrements or reloads to zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mmc3IrqBehavior {
+    Sharp,
+    Nec,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Board {
+    TxRom,
+    // 1K of RAM inside the mapper with per-half protection
+    Mmc6,
+    // CHR bank bit 7 drives CIRAM A10
+    TxSrom,
+    // CHR bank bit 6 picks 8K of CHR-RAM instead of ROM
+    Tqrom,
+}
+
+// Mapper 4 (TxROM/MMC3 and MMC6), 118 (TxSROM) and 119 (TQROM)
+pub struct Mmc3 {
+    board: Board,
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    chr_ram: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+    four_screen: bool,
+
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+    ram_protect: u8,
+
+    irq_behavior: Mmc3IrqBehavior,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    a12_high: bool,
+    a12_low_fetches: u8,
+}
+
+impl Mmc3 {
+    pub fn new(rom: ROM) -> Self {
+        let board = match (rom.mapper, rom.submapper) {
+            (118, _) => Board::TxSrom,
+            (119, _) => Board::Tqrom,
+            (_, 1) => Board::Mmc6,
+            _ => Board::TxRom,
+        };
+        let irq_behavior = if rom.submapper == 4 {
+            Mmc3IrqBehavior::Nec
+        } else {
+            Mmc3IrqBehavior::Sharp
+        };
+        let prg_ram = if board == Board::Mmc6 {
+            PrgRam::new(MMC6_RAM_SIZE)
+        } else {
+            PrgRam::for_rom(&rom)
+        };
+        let tqrom_chr_ram_size = if board == Board::Tqrom {
+            TQROM_CHR_RAM_SIZE
+        } else {
+            0
+        };
+
+        Mmc3 {
+            board,
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            chr_ram: ChrMem::new(vec![], tqrom_chr_ram_size),
+            prg_ram,
+            battery: rom.battery,
+            four_screen: rom.mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: rom.mirroring,
+            ram_protect: 0,
+            irq_behavior,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12_high: false,
+            a12_low_fetches: 0,
+        }
+    }
+
+    pub fn set_irq_behavior(&mut self, behavior: Mmc3IrqBehavior) {
+        self.irq_behavior = behavior;
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        // PRG-ROM under 16K just mirrors, bank_offset wraps these
+        let second_last = (self.prg_rom.len() / PRG_BANK_SIZE).saturating_sub(2);
+        let swap = self.bank_select & 0b0100_0000 != 0;
+
+        match (addr, swap) {
+            (0x8000..=0x9fff, false) | (0xc000..=0xdfff, true) => self.registers[6] as usize,
+            (0x8000..=0x9fff, true) | (0xc000..=0xdfff, false) => second_last,
+            (0xa000..=0xbfff, _) => self.registers[7] as usize,
+            _ => second_last + 1,
+        }
+    }
+
+    // R0/R1 are 2K banks and R2-R5 1K banks; inversion swaps which pattern
+    // table gets which
+    fn chr_bank(&self, addr: u16) -> u8 {
+        let addr = if self.bank_select & 0b1000_0000 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+
+        match addr {
+            0x0000..=0x07ff => (self.registers[0] & !1) | ((addr >> 10) as u8 & 1),
+            0x0800..=0x0fff => (self.registers[1] & !1) | ((addr >> 10) as u8 & 1),
+            _ => self.registers[2 + ((addr as usize - 0x1000) >> 10)],
+        }
+    }
+
+    fn chr_is_ram(&self, bank: u8) -> bool {
+        self.board == Board::Tqrom && bank & 0b0100_0000 != 0
+    }
+
+    fn update_mirroring(&mut self) {
+        if self.board != Board::TxSrom {
+            return;
+        }
+
+        let mut pages = [0; 4];
+        for (nametable, page) in pages.iter_mut().enumerate() {
+            *page = self.chr_bank(nametable as u16 * CHR_BANK_SIZE as u16) >> 7;
+        }
+        self.mirroring = Mirroring::Custom(pages);
+    }
+
+    fn update_prg_ram(&mut self) {
+        if self.board == Board::Mmc6 {
+            return;
+        }
+
+        self.prg_ram
+            .set_enabled(self.ram_protect & 0b1000_0000 != 0);
+        self.prg_ram
+            .set_write_protected(self.ram_protect & 0b0100_0000 != 0);
+    }
+
+    // MMC6 RAM sits at $7000-$7FFF and is only on when bit 5 of bank select
+    // is set. $A001 has read and write enables for each 512 byte half, and a
+    // half can only be written while it is also readable. Returns None when
+    // neither half is readable, which leaves the bus open.
+    fn mmc6_half_access(&self, addr: u16) -> Option<(bool, bool)> {
+        if addr < 0x7000
+            || self.bank_select & 0b0010_0000 == 0
+            || self.ram_protect & 0b1010_0000 == 0
+        {
+            return None;
+        }
+
+        let shift = if addr & 0x200 != 0 { 6 } else { 4 };
+        let readable = self.ram_protect & (0b10 << shift) != 0;
+        let writable = self.ram_protect & (0b01 << shift) != 0;
+        Some((readable, readable && writable))
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let was_zero = self.irq_counter == 0;
+        let reloading = self.irq_reload;
+
+        if was_zero || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        let fire = match self.irq_behavior {
+            Mmc3IrqBehavior::Sharp => self.irq_counter == 0,
+            Mmc3IrqBehavior::Nec => self.irq_counter == 0 && (!was_zero || reloading),
+        };
+
+        if fire && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000..=0x9fff, true) => {
+                self.bank_select = data;
+                self.update_mirroring();
+            }
+            (0x8000..=0x9fff, false) => {
+                self.registers[(self.bank_select & 0b111) as usize] = data;
+                self.update_mirroring();
+            }
+            (0xa000..=0xbfff, true) => {
+                if !self.four_screen && self.board != Board::TxSrom {
+                    self.mirroring = if data & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            (0xa000..=0xbfff, false) => {
+                self.ram_protect = data;
+                self.update_prg_ram();
+            }
+            (0xc000..=0xdfff, true) => self.irq_latch = data,
+            (0xc000..=0xdfff, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff if self.board == Board::Mmc6 => {
+                // A disabled half reads as 0 while the other one is enabled
+                let (readable, _) = self.mmc6_half_access(addr)?;
+                if readable {
+                    self.prg_ram.read(addr)
+                } else {
+                    Some(0)
+                }
+            }
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank = self.prg_bank(addr);
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff if self.board == Board::Mmc6 => {
+                if let Some((_, true)) = self.mmc6_half_access(addr) {
+                    self.prg_ram.write(addr, data);
+                }
+            }
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_bank(addr);
+        if self.chr_is_ram(bank) {
+            return self.chr_ram.read(bank as usize, CHR_BANK_SIZE, addr);
+        }
+
+        let bank = if self.board == Board::Tqrom {
+            bank & 0b0011_1111
+        } else {
+            bank
+        };
+        self.chr.read(bank as usize, CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let bank = self.chr_bank(addr);
+        if self.chr_is_ram(bank) {
+            self.chr_ram.write(bank as usize, CHR_BANK_SIZE, addr, data);
+        } else {
+            self.chr.write(bank as usize, CHR_BANK_SIZE, addr, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn notify_ppu_addr(&mut self, addr: u16) {
+        let a12_high = addr & 0x1000 != 0;
+
+        if a12_high && !self.a12_high && self.a12_low_fetches >= A12_LOW_FETCHES {
+            self.clock_irq_counter();
+        }
+
+        if a12_high {
+            self.a12_low_fetches = 0;
+        } else {
+            self.a12_low_fetches = self.a12_low_fetches.saturating_add(1);
+        }
+        self.a12_high = a12_high;
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.prg_ram.save_state(state);
+        self.chr.save_state(state);
+        self.chr_ram.save_state(state);
+        state.write_u8(self.bank_select);
+        state.write_bytes(&self.registers);
+        state.write_u8(self.ram_protect);
+        state.write_bool(self.mirroring == Mirroring::Horizontal);
+        state.write_u8(self.irq_latch);
+        state.write_u8(self.irq_counter);
+        state.write_bool(self.irq_reload);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+        state.write_bool(self.a12_high);
+        state.write_u8(self.a12_low_fetches);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.prg_ram.load_state(state)?;
+        self.chr.load_state(state)?;
+        self.chr_ram.load_state(state)?;
+        self.bank_select = state.read_u8()?;
+        state.read_bytes_into(&mut self.registers)?;
+        self.ram_protect = state.read_u8()?;
+        let horizontal = state.read_bool()?;
+        if !self.four_screen && self.board != Board::TxSrom {
+            self.mirroring = if horizontal {
+                Mirroring::Horizontal
+            } else {
+                Mirroring::Vertical
+            };
+        }
+        self.update_mirroring();
+        self.irq_latch = state.read_u8()?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_reload = state.read_bool()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.a12_high = state.read_bool()?;
+        self.a12_low_fetches = state.read_u8()?;
+        Ok(())
+    }
+
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(self.prg_ram.data())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    // Background fetches from $0xxx followed by a sprite fetch from $1xxx,
+    // the A12 pattern of one rendered scanline
+    fn scanline(mapper: &mut Mmc3) {
+        for _ in 0..A12_LOW_FETCHES + 1 {
+            mapper.notify_ppu_addr(0x0000);
+        }
+        mapper.notify_ppu_addr(0x1000);
+    }
+
+    #[test]
+    fn test_prg_mode_swaps_8000_and_c000() {
+        let mut mapper = Mmc3::new(test_rom(4, 0, 8, 0x2000, 8, 0x0400));
+        mapper.cpu_write(0x8000, 6);
+        mapper.cpu_write(0x8001, 3);
+        mapper.cpu_write(0x8000, 7);
+        mapper.cpu_write(0x8001, 4);
+        assert_eq!(mapper.cpu_read(0x8000), Some(3));
+        assert_eq!(mapper.cpu_read(0xa000), Some(4));
+        assert_eq!(mapper.cpu_read(0xc000), Some(6));
+        assert_eq!(mapper.cpu_read(0xe000), Some(7));
+
+        mapper.cpu_write(0x8000, 0b0100_0000);
+        assert_eq!(mapper.cpu_read(0x8000), Some(6));
+        assert_eq!(mapper.cpu_read(0xa000), Some(4));
+        assert_eq!(mapper.cpu_read(0xc000), Some(3));
+    }
+
+    #[test]
+    fn test_prg_rom_under_16k_mirrors() {
+        let mut rom = test_rom(4, 0, 2, 0x4000, 8, 0x0400);
+        rom.prg_rom = vec![0x42; 0x2000];
+        let mut mapper = Mmc3::new(rom);
+        assert_eq!(mapper.cpu_read(0xc000), Some(0x42));
+        assert_eq!(mapper.cpu_read(0xffff), Some(0x42));
+    }
+
+    #[test]
+    fn test_chr_inversion_swaps_pattern_tables() {
+        let mut mapper = Mmc3::new(test_rom(4, 0, 8, 0x2000, 16, 0x0400));
+        mapper.cpu_write(0x8000, 0);
+        mapper.cpu_write(0x8001, 8);
+        mapper.cpu_write(0x8000, 2);
+        mapper.cpu_write(0x8001, 12);
+        assert_eq!(mapper.ppu_read(0x0000), 8);
+        assert_eq!(mapper.ppu_read(0x0400), 9);
+        assert_eq!(mapper.ppu_read(0x1000), 12);
+
+        mapper.cpu_write(0x8000, 0b1000_0000);
+        assert_eq!(mapper.ppu_read(0x1000), 8);
+        assert_eq!(mapper.ppu_read(0x1400), 9);
+        assert_eq!(mapper.ppu_read(0x0000), 12);
+    }
+
+    #[test]
+    fn test_irq_counts_a12_rises() {
+        let mut mapper = Mmc3::new(test_rom(4, 0, 8, 0x2000, 8, 0x0400));
+        mapper.cpu_write(0xc000, 2);
+        mapper.cpu_write(0xc001, 0);
+        mapper.cpu_write(0xe001, 0);
+
+        // Reload to 2, then 1, then 0
+        scanline(&mut mapper);
+        scanline(&mut mapper);
+        assert!(!mapper.irq_pending());
+        scanline(&mut mapper);
+        assert!(mapper.irq_pending());
+
+        // A12 dropping for a single fetch doesn't count as a new rise
+        mapper.cpu_write(0xe000, 0);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_write(0xe001, 0);
+        mapper.notify_ppu_addr(0x0000);
+        mapper.notify_ppu_addr(0x1000);
+        assert_eq!(mapper.irq_counter, 0);
+    }
+
+    #[test]
+    fn test_latch_of_zero_fires_every_line_only_on_sharp() {
+        for (behavior, second_fires) in [
+            (Mmc3IrqBehavior::Sharp, true),
+            (Mmc3IrqBehavior::Nec, false),
+        ] {
+            let mut mapper = Mmc3::new(test_rom(4, 0, 8, 0x2000, 8, 0x0400));
+            mapper.set_irq_behavior(behavior);
+            mapper.cpu_write(0xc000, 0);
+            mapper.cpu_write(0xc001, 0);
+            mapper.cpu_write(0xe001, 0);
+
+            scanline(&mut mapper);
+            assert!(mapper.irq_pending());
+            mapper.cpu_write(0xe000, 0);
+            mapper.cpu_write(0xe001, 0);
+            scanline(&mut mapper);
+            assert_eq!(mapper.irq_pending(), second_fires);
+        }
+    }
+
+    #[test]
+    fn test_mmc6_ram_halves_have_own_protection() {
+        let mut mapper = Mmc3::new(test_rom(4, 1, 8, 0x2000, 8, 0x0400));
+        assert_eq!(mapper.cpu_read(0x7000), None);
+
+        // Enable the RAM, then make only the low half readable and writable
+        mapper.cpu_write(0x8000, 0b0010_0000);
+        mapper.cpu_write(0xa001, 0b0011_0000);
+        mapper.cpu_write(0x7000, 0x11);
+        mapper.cpu_write(0x7200, 0x22);
+        assert_eq!(mapper.cpu_read(0x7000), Some(0x11));
+        assert_eq!(mapper.cpu_read(0x7200), Some(0));
+
+        // Low half read-only, high half read-write
+        mapper.cpu_write(0xa001, 0b1110_0000);
+        mapper.cpu_write(0x7000, 0x33);
+        mapper.cpu_write(0x7200, 0x22);
+        assert_eq!(mapper.cpu_read(0x7000), Some(0x11));
+        assert_eq!(mapper.cpu_read(0x7200), Some(0x22));
+        // The 1K repeats through $7000-$7FFF
+        assert_eq!(mapper.cpu_read(0x7e00), Some(0x22));
+
+        // Clearing the enable in bank select shuts both halves off
+        mapper.cpu_write(0x8000, 0);
+        assert_eq!(mapper.cpu_read(0x7000), None);
+    }
+}
@@ -0,0 +1,799 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const EXRAM_SIZE: usize = 0x400;
+// Split screen and extended attribute tiles use 4K banks
+const TILE_CHR_BANK_SIZE: usize = 0x1000;
+// Fetches the PPU makes after a scanline is detected: 32 background tiles of
+// 4 fetches, 8 sprites of 4, then the first 2 tiles of the next line
+const BG_FETCHES_END: u16 = 32 * 4;
+const SPRITE_FETCHES_END: u16 = BG_FETCHES_END + 8 * 4;
+const PREFETCH_END: u16 = SPRITE_FETCHES_END + 2 * 4;
+// The frame is over once the PPU stops reading for this many CPU cycles
+const PPU_IDLE_CYCLES: u8 = 3;
+
+// Mapper 5 (ExROM). The MMC5 has no idea what the PPU is doing, so it works
+// out the current scanline and whether a fetch is for the background or for
+// sprites by watching the PPU address bus.
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: Vec<u8>,
+    exram: [u8; EXRAM_SIZE],
+    battery: bool,
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_palette: u8,
+    // $5113-$5117. Bit 7 picks ROM over RAM; $5117 is always ROM.
+    prg_banks: [u8; 5],
+    // $5120-$5127 for sprites and $5128-$512B for the background, each with
+    // the upper bits from $5130 at the time of the write
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    bg_banks_written_last: bool,
+    sprite_8x16: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_scanline: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8,
+
+    last_ppu_addr: u16,
+    nametable_repeats: u8,
+    fetch_count: u16,
+    ppu_read_seen: bool,
+    ppu_idle_cycles: u8,
+    // Latched on each background nametable fetch for the rest of the tile
+    tile_exram: u8,
+    split_tile: Option<u16>,
+    split_fine_y: u8,
+
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl Mmc5 {
+    pub fn new(rom: ROM) -> Self {
+        Mmc5 {
+            // Banked by hand since RAM can also appear at $8000-$DFFF
+            prg_ram: PrgRam::for_rom(&rom).data().to_vec(),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            exram: [0; EXRAM_SIZE],
+            battery: rom.battery,
+            prg_mode: 3,
+            chr_mode: 3,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_palette: 0,
+            prg_banks: [0, 0, 0, 0, 0xff],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            bg_banks_written_last: false,
+            sprite_8x16: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_scanline: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            last_ppu_addr: 0,
+            nametable_repeats: 0,
+            fetch_count: 0,
+            ppu_read_seen: false,
+            ppu_idle_cycles: 0,
+            tile_exram: 0,
+            split_tile: None,
+            split_fine_y: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+        }
+    }
+
+    // Register value for the 8K window holding `addr`, with the low bits of
+    // larger banks filled in
+    fn prg_bank(&self, addr: u16) -> u8 {
+        let window = ((addr - 0x8000) as usize / PRG_BANK_SIZE) as u8;
+        match (self.prg_mode, window) {
+            (0, _) => (self.prg_banks[4] & !0b11) | window,
+            (1, 0..=1) | (2, 0..=1) => (self.prg_banks[2] & !1) | (window & 1),
+            (1, _) => (self.prg_banks[4] & !1) | (window & 1),
+            (2, 2) => self.prg_banks[3],
+            (2, _) => self.prg_banks[4],
+            (_, window) => self.prg_banks[1 + window as usize],
+        }
+    }
+
+    fn prg_ram_offset(&self, bank: u8, addr: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() {
+            return None;
+        }
+
+        Some(bank_offset(
+            self.prg_ram.len(),
+            (bank & 0b0111) as usize,
+            PRG_BANK_SIZE,
+            addr,
+        ))
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0b10, 0b01]
+    }
+
+    fn bg_fetch(&self) -> bool {
+        self.fetch_count < BG_FETCHES_END
+            || (SPRITE_FETCHES_END..PREFETCH_END).contains(&self.fetch_count)
+    }
+
+    // Only true while the PPU is rendering, so CPU accesses through $2007
+    // between frames don't get mistaken for tile fetches
+    fn rendering_bg(&self) -> bool {
+        self.in_frame && self.bg_fetch()
+    }
+
+    // In 8x8 sprite mode the last set of banks written is used for
+    // everything
+    fn use_bg_banks(&self) -> bool {
+        if self.sprite_8x16 && self.in_frame {
+            self.bg_fetch()
+        } else {
+            self.bg_banks_written_last
+        }
+    }
+
+    // Bank and bank size for a fetch through the $5120-$512B registers
+    fn chr_bank(&self, addr: u16) -> (usize, usize) {
+        let (bank_size, register) = match self.chr_mode {
+            0 => (0x2000, 7),
+            1 => (0x1000, 3 | ((addr >> 12) << 2)),
+            2 => (0x0800, 1 | ((addr >> 11) << 1)),
+            _ => (0x0400, addr >> 10),
+        };
+        // The four background registers cover both pattern tables
+        let register = if self.use_bg_banks() {
+            8 + (register & 0b11)
+        } else {
+            register
+        };
+
+        (self.chr_banks[register as usize] as usize, bank_size)
+    }
+
+    fn detect_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_scanline {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        }
+    }
+
+    // Called on every background nametable fetch. The first two tiles of a
+    // line are fetched at the end of the previous one.
+    fn latch_tile(&mut self, addr: u16) {
+        self.tile_exram = self.exram[(addr & 0x3ff) as usize];
+
+        let (tile, line) = if self.fetch_count < BG_FETCHES_END {
+            (self.fetch_count / 4 + 2, self.scanline as u16)
+        } else {
+            (
+                (self.fetch_count - SPRITE_FETCHES_END) / 4,
+                self.scanline as u16 + 1,
+            )
+        };
+
+        let threshold = (self.split_control & 0b1_1111) as u16;
+        let in_split = if self.split_control & 0b0100_0000 != 0 {
+            tile >= threshold
+        } else {
+            tile < threshold
+        };
+
+        self.split_tile = None;
+        if self.split_control & 0b1000_0000 != 0 && self.exram_mode <= 1 && in_split {
+            let y = (self.split_scroll as u16 + line) % 240;
+            self.split_tile = Some((y / 8) * 32 + (tile & 31));
+            self.split_fine_y = (y & 0b111) as u8;
+        }
+    }
+
+    // Palette bits repeated for every quadrant, since the PPU picks the
+    // quadrant from its own scroll position
+    fn attribute_byte(palette: u8) -> u8 {
+        (palette & 0b11) * 0b0101_0101
+    }
+
+    fn split_attribute(&self, tile: u16) -> u8 {
+        let row = tile / 32;
+        let column = tile % 32;
+        let attribute = self.exram[(0x3c0 + (row / 4) * 8 + column / 4) as usize];
+        let shift = ((row & 0b10) << 1) | (column & 0b10);
+        Self::attribute_byte(attribute >> shift)
+    }
+
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        let data = self.peek_register(addr);
+        if addr == 0x5204 {
+            self.irq_pending = false;
+        }
+        data
+    }
+
+    fn peek_register(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => Some(((self.irq_pending as u8) << 7) | ((self.in_frame as u8) << 6)),
+            0x5205 => Some((self.multiplicand as u16 * self.multiplier as u16) as u8),
+            0x5206 => Some(((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8),
+            // ExRAM is only readable by the CPU in its RAM modes
+            0x5c00..=0x5fff if self.exram_mode >= 2 => Some(self.exram[(addr - 0x5c00) as usize]),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5102 => self.prg_ram_protect[0] = data & 0b11,
+            0x5103 => self.prg_ram_protect[1] = data & 0b11,
+            0x5104 => self.exram_mode = data & 0b11,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_palette = data & 0b11,
+            0x5113..=0x5116 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5117 => self.prg_banks[4] = data | 0b1000_0000,
+            0x5120..=0x512b => {
+                self.chr_banks[(addr - 0x5120) as usize] =
+                    data as u16 | ((self.chr_upper as u16) << 8);
+                self.bg_banks_written_last = addr >= 0x5128;
+            }
+            0x5130 => self.chr_upper = data & 0b11,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_scanline = data,
+            0x5204 => self.irq_enabled = data & 0b1000_0000 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5c00..=0x5fff => {
+                // In the nametable modes writes only land while rendering
+                let index = (addr - 0x5c00) as usize;
+                match self.exram_mode {
+                    0 | 1 => self.exram[index] = if self.in_frame { data } else { 0 },
+                    2 => self.exram[index] = data,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5000..=0x5fff => self.read_register(addr),
+            // Fetching the NMI vector means vblank has started
+            0xfffa | 0xfffb => {
+                self.in_frame = false;
+                self.cpu_peek(addr)
+            }
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5000..=0x5fff => self.peek_register(addr),
+            0x6000..=0x7fff => {
+                let offset = self.prg_ram_offset(self.prg_banks[0], addr)?;
+                Some(self.prg_ram[offset])
+            }
+            0x8000..=0xffff => {
+                let bank = self.prg_bank(addr);
+                if bank & 0b1000_0000 != 0 {
+                    let bank = (bank & 0b0111_1111) as usize;
+                    Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+                } else {
+                    let offset = self.prg_ram_offset(bank, addr)?;
+                    Some(self.prg_ram[offset])
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let bank = match addr {
+            0x5000..=0x5fff => return self.write_register(addr, data),
+            0x6000..=0x7fff => self.prg_banks[0],
+            0x8000..=0xdfff => self.prg_bank(addr),
+            _ => return,
+        };
+
+        let rom = addr >= 0x8000 && bank & 0b1000_0000 != 0;
+        if rom || !self.prg_ram_writable() {
+            return;
+        }
+        if let Some(offset) = self.prg_ram_offset(bank, addr) {
+            self.prg_ram[offset] = data;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        match self.split_tile {
+            Some(_) if self.rendering_bg() => {
+                // The PPU's fine Y is for the scrolled screen, not the split
+                let addr = (addr & 0x0ff8) | self.split_fine_y as u16;
+                self.chr
+                    .read(self.split_bank as usize, TILE_CHR_BANK_SIZE, addr)
+            }
+            _ if self.exram_mode == 1 && self.rendering_bg() => {
+                let bank =
+                    (self.tile_exram & 0b11_1111) as usize | ((self.chr_upper as usize) << 6);
+                self.chr.read(bank, TILE_CHR_BANK_SIZE, addr)
+            }
+            _ => {
+                let (bank, bank_size) = self.chr_bank(addr);
+                self.chr.read(bank, bank_size, addr)
+            }
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let (bank, bank_size) = self.chr_bank(addr);
+        self.chr.write(bank, bank_size, addr, data);
+    }
+
+    // Nametables mapped to ExRAM or fill mode are answered by
+    // nametable_read, so only the CIRAM pages matter here
+    fn mirroring(&self) -> Mirroring {
+        let mut pages = [0; 4];
+        for (nametable, page) in pages.iter_mut().enumerate() {
+            *page = (self.nametable_mapping >> (nametable * 2)) & 1;
+        }
+        Mirroring::Custom(pages)
+    }
+
+    fn nametable_read(&self, addr: u16) -> Option<u8> {
+        let offset = addr & 0x3ff;
+        let attribute_fetch = self.fetch_count & 0b11 == 1;
+
+        if self.rendering_bg() {
+            if let Some(tile) = self.split_tile {
+                return Some(if attribute_fetch {
+                    self.split_attribute(tile)
+                } else {
+                    self.exram[tile as usize]
+                });
+            }
+            if self.exram_mode == 1 && attribute_fetch {
+                return Some(Self::attribute_byte(self.tile_exram >> 6));
+            }
+        }
+
+        match (self.nametable_mapping >> (((addr >> 10) & 0b11) * 2)) & 0b11 {
+            0 | 1 => None,
+            2 if self.exram_mode <= 1 => Some(self.exram[offset as usize]),
+            2 => Some(0),
+            _ if offset < 0x3c0 => Some(self.fill_tile),
+            _ => Some(Self::attribute_byte(self.fill_palette)),
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+        match (self.nametable_mapping >> (((addr >> 10) & 0b11) * 2)) & 0b11 {
+            0 | 1 => false,
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[(addr & 0x3ff) as usize] = data;
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending && self.irq_enabled
+    }
+
+    fn cpu_clock(&mut self, cycles: u8) {
+        if self.ppu_read_seen {
+            self.ppu_read_seen = false;
+            self.ppu_idle_cycles = 0;
+        } else if self.in_frame {
+            self.ppu_idle_cycles = self.ppu_idle_cycles.saturating_add(cycles);
+            if self.ppu_idle_cycles >= PPU_IDLE_CYCLES {
+                self.in_frame = false;
+            }
+        }
+    }
+
+    // Three reads in a row from the same nametable address only happen at
+    // the start of a scanline: the two dummy fetches at the end of the last
+    // line and the first fetch of the new one
+    fn notify_ppu_addr(&mut self, addr: u16) {
+        self.ppu_read_seen = true;
+
+        if (0x2000..=0x2fff).contains(&addr) && addr == self.last_ppu_addr {
+            self.nametable_repeats = self.nametable_repeats.saturating_add(1);
+        } else {
+            self.nametable_repeats = 0;
+        }
+        self.last_ppu_addr = addr;
+
+        if self.nametable_repeats == 2 {
+            self.detect_scanline();
+            self.fetch_count = 0;
+        } else {
+            self.fetch_count = self.fetch_count.saturating_add(1);
+        }
+
+        if self.rendering_bg() && self.fetch_count & 0b11 == 0 {
+            self.latch_tile(addr);
+        }
+    }
+
+    fn notify_ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x2000 => self.sprite_8x16 = data & 0b0010_0000 != 0,
+            0x2001 if data & 0b0001_1000 == 0 => self.in_frame = false,
+            _ => {}
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        state.write_bytes(&self.prg_ram);
+        state.write_bytes(&self.exram);
+        state.write_u8(self.prg_mode);
+        state.write_u8(self.chr_mode);
+        state.write_bytes(&self.prg_ram_protect);
+        state.write_u8(self.exram_mode);
+        state.write_u8(self.nametable_mapping);
+        state.write_u8(self.fill_tile);
+        state.write_u8(self.fill_palette);
+        state.write_bytes(&self.prg_banks);
+        for bank in self.chr_banks.iter() {
+            state.write_u16(*bank);
+        }
+        state.write_u8(self.chr_upper);
+        state.write_bool(self.bg_banks_written_last);
+        state.write_bool(self.sprite_8x16);
+        state.write_u8(self.split_control);
+        state.write_u8(self.split_scroll);
+        state.write_u8(self.split_bank);
+        state.write_u8(self.irq_scanline);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+        state.write_bool(self.in_frame);
+        state.write_u8(self.scanline);
+        state.write_u16(self.last_ppu_addr);
+        state.write_u8(self.nametable_repeats);
+        state.write_u16(self.fetch_count);
+        state.write_u8(self.tile_exram);
+        state.write_u16(self.split_tile.unwrap_or(0xffff));
+        state.write_u8(self.split_fine_y);
+        state.write_u8(self.multiplicand);
+        state.write_u8(self.multiplier);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        state.read_bytes_into(&mut self.prg_ram)?;
+        state.read_bytes_into(&mut self.exram)?;
+        self.prg_mode = state.read_u8()? & 0b11;
+        self.chr_mode = state.read_u8()? & 0b11;
+        state.read_bytes_into(&mut self.prg_ram_protect)?;
+        self.exram_mode = state.read_u8()? & 0b11;
+        self.nametable_mapping = state.read_u8()?;
+        self.fill_tile = state.read_u8()?;
+        self.fill_palette = state.read_u8()? & 0b11;
+        state.read_bytes_into(&mut self.prg_banks)?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = state.read_u16()?;
+        }
+        self.chr_upper = state.read_u8()?;
+        self.bg_banks_written_last = state.read_bool()?;
+        self.sprite_8x16 = state.read_bool()?;
+        self.split_control = state.read_u8()?;
+        self.split_scroll = state.read_u8()?;
+        self.split_bank = state.read_u8()?;
+        self.irq_scanline = state.read_u8()?;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.in_frame = state.read_bool()?;
+        self.scanline = state.read_u8()?;
+        self.last_ppu_addr = state.read_u16()?;
+        self.nametable_repeats = state.read_u8()?;
+        self.fetch_count = state.read_u16()?;
+        self.tile_exram = state.read_u8()?;
+        self.split_tile = match state.read_u16()? {
+            0xffff => None,
+            tile => Some(tile % EXRAM_SIZE as u16),
+        };
+        self.split_fine_y = state.read_u8()? & 0b111;
+        self.multiplicand = state.read_u8()?;
+        self.multiplier = state.read_u8()?;
+        Ok(())
+    }
+
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(&self.prg_ram)
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    fn mmc5() -> Mmc5 {
+        let mut rom = test_rom(5, 0, 16, 0x2000, 16, 0x0400);
+        rom.prg_ram_size = 0x4000;
+        Mmc5::new(rom)
+    }
+
+    // The two dummy nametable fetches at the end of a line and the first
+    // fetch of the next one
+    fn scanline(mapper: &mut Mmc5) {
+        mapper.notify_ppu_addr(0x0000);
+        for _ in 0..3 {
+            mapper.notify_ppu_addr(0x2000);
+        }
+    }
+
+    // ExRAM can only be written outside rendering in mode 2
+    fn write_exram(mapper: &mut Mmc5, offset: u16, data: u8) {
+        let mode = mapper.exram_mode;
+        mapper.cpu_write(0x5104, 2);
+        mapper.cpu_write(0x5c00 + offset, data);
+        mapper.cpu_write(0x5104, mode);
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = mmc5();
+        assert_eq!(mapper.cpu_read(0xe000), Some(15));
+
+        mapper.cpu_write(0x5100, 0);
+        mapper.cpu_write(0x5117, 0x04);
+        let banks: Vec<_> = (0..4)
+            .map(|i| mapper.cpu_read(0x8000 + i * 0x2000))
+            .collect();
+        assert_eq!(banks, [Some(4), Some(5), Some(6), Some(7)]);
+
+        mapper.cpu_write(0x5100, 1);
+        mapper.cpu_write(0x5115, 0x87);
+        mapper.cpu_write(0x5117, 0x0b);
+        let banks: Vec<_> = (0..4)
+            .map(|i| mapper.cpu_read(0x8000 + i * 0x2000))
+            .collect();
+        assert_eq!(banks, [Some(6), Some(7), Some(10), Some(11)]);
+
+        mapper.cpu_write(0x5100, 2);
+        mapper.cpu_write(0x5116, 0x89);
+        let banks: Vec<_> = (0..4)
+            .map(|i| mapper.cpu_read(0x8000 + i * 0x2000))
+            .collect();
+        assert_eq!(banks, [Some(6), Some(7), Some(9), Some(11)]);
+
+        mapper.cpu_write(0x5100, 3);
+        mapper.cpu_write(0x5114, 0x81);
+        mapper.cpu_write(0x5115, 0x82);
+        mapper.cpu_write(0x5116, 0x83);
+        let banks: Vec<_> = (0..4)
+            .map(|i| mapper.cpu_read(0x8000 + i * 0x2000))
+            .collect();
+        assert_eq!(banks, [Some(1), Some(2), Some(3), Some(11)]);
+    }
+
+    #[test]
+    fn test_prg_ram_banks_and_write_protect() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5113, 1);
+        mapper.cpu_write(0x5114, 1);
+        mapper.cpu_write(0x8000, 0x42);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+
+        mapper.cpu_write(0x5102, 0b10);
+        mapper.cpu_write(0x5103, 0b01);
+        mapper.cpu_write(0x8000, 0x42);
+        assert_eq!(mapper.cpu_read(0x8000), Some(0x42));
+        assert_eq!(mapper.cpu_read(0x6000), Some(0x42));
+
+        // ROM banks ignore writes even while RAM is unlocked
+        mapper.cpu_write(0x5114, 0x82);
+        mapper.cpu_write(0x8000, 0x99);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+    }
+
+    #[test]
+    fn test_exram_modes() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5104, 2);
+        mapper.cpu_write(0x5c05, 0x12);
+        assert_eq!(mapper.cpu_read(0x5c05), Some(0x12));
+
+        // Read-only
+        mapper.cpu_write(0x5104, 3);
+        mapper.cpu_write(0x5c05, 0x34);
+        assert_eq!(mapper.cpu_read(0x5c05), Some(0x12));
+
+        // Nametable mode: not readable by the CPU, and writes outside
+        // rendering store 0
+        mapper.cpu_write(0x5104, 0);
+        assert_eq!(mapper.cpu_read(0x5c05), None);
+        mapper.cpu_write(0x5105, 0b10);
+        assert_eq!(mapper.nametable_read(0x2005), Some(0x12));
+        mapper.cpu_write(0x5c05, 0x56);
+        assert_eq!(mapper.nametable_read(0x2005), Some(0));
+        assert!(mapper.nametable_write(0x2005, 0x78));
+        assert_eq!(mapper.nametable_read(0x2005), Some(0x78));
+        // The other nametables stay in CIRAM
+        assert_eq!(mapper.nametable_read(0x2405), None);
+    }
+
+    #[test]
+    fn test_fill_mode_nametable() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5105, 0b1100);
+        mapper.cpu_write(0x5106, 0x77);
+        mapper.cpu_write(0x5107, 2);
+        assert_eq!(mapper.nametable_read(0x2000), None);
+        assert_eq!(mapper.nametable_read(0x2400), Some(0x77));
+        assert_eq!(mapper.nametable_read(0x27c0), Some(0xaa));
+    }
+
+    #[test]
+    fn test_split_screen_fetches() {
+        let mut mapper = mmc5();
+        // Split the first 4 tiles, scrolled to row 2 of the split nametable
+        // and using CHR bank 1
+        mapper.cpu_write(0x5200, 0x80 | 4);
+        mapper.cpu_write(0x5201, 19);
+        mapper.cpu_write(0x5202, 1);
+        mapper.cpu_write(0x5128, 9);
+        write_exram(&mut mapper, 2 * 32 + 2, 0x42);
+        write_exram(&mut mapper, 2 * 32 + 3, 0x43);
+        write_exram(&mut mapper, 0x3c0, 0b11 << 6);
+
+        // The first fetch of the line is for tile 2
+        scanline(&mut mapper);
+        assert_eq!(mapper.nametable_read(0x2000), Some(0x42));
+        mapper.notify_ppu_addr(0x23c0);
+        assert_eq!(mapper.nametable_read(0x23c0), Some(0xff));
+        mapper.notify_ppu_addr(0x0420);
+        assert_eq!(mapper.ppu_read(0x0420), 5);
+        mapper.notify_ppu_addr(0x0428);
+
+        // Tile 3 is still split, tile 4 isn't
+        mapper.notify_ppu_addr(0x2000);
+        assert_eq!(mapper.nametable_read(0x2000), Some(0x43));
+        for addr in [0x23c0, 0x0000, 0x0008, 0x2000] {
+            mapper.notify_ppu_addr(addr);
+        }
+        assert_eq!(mapper.nametable_read(0x2000), None);
+        assert_eq!(mapper.ppu_read(0x0000), 9);
+    }
+
+    #[test]
+    fn test_exram_extended_attributes() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5104, 1);
+        mapper.cpu_write(0x5128, 9);
+        // Palette 2 and 4K CHR bank 3 for the tile at $2005
+        write_exram(&mut mapper, 5, 0b1000_0011);
+
+        scanline(&mut mapper);
+        for addr in [0x23c0, 0x0000, 0x0008, 0x2005] {
+            mapper.notify_ppu_addr(addr);
+        }
+        // The nametable itself is still in CIRAM
+        assert_eq!(mapper.nametable_read(0x2005), None);
+        mapper.notify_ppu_addr(0x23c1);
+        assert_eq!(mapper.nametable_read(0x23c1), Some(0xaa));
+        mapper.notify_ppu_addr(0x0010);
+        assert_eq!(mapper.ppu_read(0x0010), 12);
+
+        // CPU accesses through $2007 outside rendering use the registers
+        mapper.notify_ppu_register_write(0x2001, 0);
+        assert_eq!(mapper.ppu_read(0x0010), 9);
+    }
+
+    #[test]
+    fn test_8x16_sprites_use_both_bank_sets() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5120, 1);
+        mapper.cpu_write(0x5128, 9);
+
+        // With 8x8 sprites the last set written is used for everything
+        scanline(&mut mapper);
+        assert_eq!(mapper.ppu_read(0x0000), 9);
+        mapper.cpu_write(0x5120, 1);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+
+        mapper.notify_ppu_register_write(0x2000, 0x20);
+        assert_eq!(mapper.ppu_read(0x0000), 9);
+        while mapper.fetch_count < BG_FETCHES_END {
+            mapper.notify_ppu_addr(0x0000);
+        }
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+        while mapper.fetch_count < SPRITE_FETCHES_END {
+            mapper.notify_ppu_addr(0x0000);
+        }
+        assert_eq!(mapper.ppu_read(0x0000), 9);
+
+        // Outside rendering it's back to the last set written
+        mapper.notify_ppu_register_write(0x2001, 0);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mapper = mmc5();
+        assert_eq!(mapper.cpu_read(0x5205), Some(0x01));
+        assert_eq!(mapper.cpu_read(0x5206), Some(0xfe));
+
+        mapper.cpu_write(0x5205, 200);
+        mapper.cpu_write(0x5206, 100);
+        assert_eq!(mapper.cpu_read(0x5205), Some(0x20));
+        assert_eq!(mapper.cpu_read(0x5206), Some(0x4e));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5203, 2);
+        mapper.cpu_write(0x5204, 0x80);
+
+        scanline(&mut mapper);
+        scanline(&mut mapper);
+        assert!(!mapper.irq_pending());
+        scanline(&mut mapper);
+        assert!(mapper.irq_pending());
+
+        // Reading the status acknowledges the IRQ
+        assert_eq!(mapper.cpu_read(0x5204), Some(0b1100_0000));
+        assert!(!mapper.irq_pending());
+        assert_eq!(mapper.cpu_read(0x5204), Some(0b0100_0000));
+
+        // The frame ends once the PPU stops fetching
+        mapper.cpu_clock(1);
+        mapper.cpu_clock(PPU_IDLE_CYCLES);
+        assert_eq!(mapper.cpu_read(0x5204), Some(0));
+    }
+}
@@ -0,0 +1,225 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::savestate::{StateReader, StateWriter};
+
+mod axrom;
+mod bandai_fcg;
+mod bnrom;
+mod cnrom;
+mod color_dreams;
+mod fds;
+mod fme7;
+mod gxrom;
+mod mmc1;
+mod mmc2;
+mod mmc3;
+mod mmc5;
+mod namco163;
+mod nrom;
+mod nsf;
+mod unrom512;
+mod uxrom;
+mod vrc4;
+
+pub use axrom::Axrom;
+pub use bandai_fcg::BandaiFcg;
+pub use bnrom::{Bnrom, Nina001};
+pub use cnrom::Cnrom;
+pub use color_dreams::ColorDreams;
+pub use fds::Fds;
+pub use fme7::Fme7;
+pub use gxrom::Gxrom;
+pub use mmc1::Mmc1;
+pub use mmc2::Mmc2;
+pub use mmc3::{Mmc3, Mmc3IrqBehavior};
+pub use mmc5::Mmc5;
+pub use namco163::Namco163;
+pub use nrom::Nrom;
+pub use nsf::{Nsf, PLAYER_RETURN_ADDR};
+pub use unrom512::Unrom512;
+pub use uxrom::Uxrom;
+pub use vrc4::Vrc4;
+
+// Everything on the cartridge side of the CPU and PPU buses. The Bus forwards
+// $4020-$FFFF and PPU $0000-$1FFF here, so new boards only need a new impl.
+pub trait Mapper {
+    // None when the board doesn't drive the data bus, which reads as open bus
+    fn cpu_read(&mut self, addr: u16) -> Option<u8>;
+
+    // Same as cpu_read but without side effects, for debuggers
+    fn cpu_peek(&self, addr: u16) -> Option<u8>;
+
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Boards with their own nametable memory answer $2000-$2FFF here. None
+    // falls back to CIRAM arranged by mirroring().
+    fn nametable_read(&self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    // Returns true when the board took the write instead of CIRAM
+    fn nametable_write(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Called after each CPU instruction with the cycles it took
+    fn cpu_clock(&mut self, _cycles: u8) {}
+
+    // Called once per rendered scanline, for boards that count them directly
+    fn notify_scanline(&mut self) {}
+
+    // Called with every address the PPU puts on its bus, so boards can watch
+    // A12 or the tiles being fetched
+    fn notify_ppu_addr(&mut self, _addr: u16) {}
+
+    // Called with CPU writes to $2000-$2007, for boards that snoop PPU
+    // settings such as sprite size
+    fn notify_ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn save_state(&self, state: &mut StateWriter);
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String>;
+
+    // Battery-backed memory to persist between sessions, if the board has any
+    fn battery_data(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn load_battery_data(&mut self, _data: &[u8]) {}
+
+    // Disk System sides, for the frontend to flip the disk. None ejects it.
+    fn disk_sides(&self) -> usize {
+        0
+    }
+
+    fn current_disk_side(&self) -> Option<usize> {
+        None
+    }
+
+    fn insert_disk_side(&mut self, _side: Option<usize>) {}
+}
+
+pub fn from_rom(rom: ROM) -> Result<Box<dyn Mapper>, String> {
+    match rom.mapper {
+        0 => Ok(Box::new(Nrom::new(rom))),
+        1 => Ok(Box::new(Mmc1::new(rom))),
+        2 => Ok(Box::new(Uxrom::new(rom))),
+        3 => Ok(Box::new(Cnrom::new(rom))),
+        4 | 118 | 119 => Ok(Box::new(Mmc3::new(rom))),
+        5 => Ok(Box::new(Mmc5::new(rom))),
+        7 => Ok(Box::new(Axrom::new(rom))),
+        9 | 10 => Ok(Box::new(Mmc2::new(rom))),
+        11 => Ok(Box::new(ColorDreams::new(rom))),
+        16 | 153 | 157 | 159 => Ok(Box::new(BandaiFcg::new(rom))),
+        19 => Ok(Box::new(Namco163::new(rom))),
+        21 | 22 | 23 | 25 => Ok(Box::new(Vrc4::new(rom))),
+        30 => Ok(Box::new(Unrom512::new(rom))),
+        // Submapper 1 is NINA-001, 2 is BNROM. Older headers only tell them
+        // apart by NINA-001 having CHR-ROM.
+        34 if rom.submapper == 1 || (rom.submapper == 0 && rom.chr_rom.len() > 0x2000) => {
+            Ok(Box::new(Nina001::new(rom)))
+        }
+        34 => Ok(Box::new(Bnrom::new(rom))),
+        66 => Ok(Box::new(Gxrom::new(rom))),
+        69 => Ok(Box::new(Fme7::new(rom))),
+        mapper => Err(format!("Mapper {} is not supported", mapper)),
+    }
+}
+
+// Offset into a ROM of `len` bytes for `addr` inside a window of `bank_size`
+// bytes showing `bank`. Out of range banks wrap like unconnected address lines,
+// including "last bank" numbers that wrapped below zero on tiny ROMs.
+pub(crate) fn bank_offset(len: usize, bank: usize, bank_size: usize, addr: u16) -> usize {
+    bank.wrapping_mul(bank_size)
+        .wrapping_add(addr as usize & (bank_size - 1))
+        % len
+}
+
+// Discrete-logic boards latch whatever is on the data bus when $8000-$FFFF is
+// written, but the ROM isn't disabled for writes and drives the bus at the
+// same time. The latch then sees the bits both agree on, which is why games
+// write to a ROM byte that already holds the value. For UxROM, CNROM and
+// UNROM 512, NES 2.0 submapper 1 marks boards wired to avoid them.
+pub(crate) fn bus_conflict(rom_data: Option<u8>, data: u8) -> u8 {
+    rom_data.map_or(data, |rom_data| data & rom_data)
+}
+
+// Builds an NES 2.0 image where every PRG and CHR bank is filled with its own
+// bank number, so tests can tell which bank is mapped by reading any byte
+#[cfg(test)]
+pub(crate) fn test_rom(
+    mapper: u16,
+    submapper: u8,
+    prg_banks: usize,
+    prg_bank_size: usize,
+    chr_banks: usize,
+    chr_bank_size: usize,
+) -> ROM {
+    let prg_size = prg_banks * prg_bank_size;
+    let chr_size = chr_banks * chr_bank_size;
+
+    let mut raw = vec![
+        0x4e,
+        0x45,
+        0x53,
+        0x1a,
+        (prg_size / 0x4000) as u8,
+        (chr_size / 0x2000) as u8,
+        ((mapper & 0x0f) << 4) as u8,
+        (mapper & 0xf0) as u8 | 0b1000,
+        (submapper << 4) | (mapper >> 8) as u8,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    for bank in 0..prg_banks {
+        raw.extend_from_slice(&vec![bank as u8; prg_bank_size]);
+    }
+    for bank in 0..chr_banks {
+        raw.extend_from_slice(&vec![bank as u8; chr_bank_size]);
+    }
+
+    ROM::new(&raw).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bank_offset_wraps_tiny_roms() {
+        // "Last bank" on a ROM smaller than one bank wraps below zero
+        let last = (0x2000 / 0x4000_usize).wrapping_sub(1);
+        assert_eq!(bank_offset(0x2000, last, 0x4000, 0xc000), 0x0000);
+        assert_eq!(bank_offset(0x2000, last, 0x4000, 0xffff), 0x1fff);
+        assert_eq!(bank_offset(0x1000, last, 0x2000, 0xe123), 0x0123);
+
+        // Banks past the end wrap like unconnected address lines
+        assert_eq!(bank_offset(0x8000, 5, 0x4000, 0x8001), 0x4001);
+    }
+
+    #[test]
+    fn test_discrete_boards_load_trainer() {
+        for mapper in [2, 3, 7, 11, 30, 34, 66] {
+            let mut rom = test_rom(mapper, 0, 2, 0x4000, 0, 0x2000);
+            rom.trainer = vec![0x42; 512];
+            let mut board = from_rom(rom).unwrap();
+            assert_eq!(board.cpu_read(0x7000), Some(0x42), "mapper {}", mapper);
+            board.cpu_write(0x6000, 0x17);
+            assert_eq!(board.cpu_read(0x6000), Some(0x17), "mapper {}", mapper);
+        }
+    }
+}
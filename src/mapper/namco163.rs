@@ -0,0 +1,330 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+const CIRAM_SIZE: usize = 0x0800;
+const INTERNAL_RAM_SIZE: usize = 0x80;
+// Bank numbers from here up select a CIRAM page instead of CHR-ROM
+const CIRAM_BANKS: u8 = 0xe0;
+const IRQ_COUNTER_MAX: u16 = 0x7fff;
+
+// Mapper 19 (Namco 163). Twelve 1K banks cover the pattern tables and the
+// four nametables, and each can show CHR-ROM or one of the two CIRAM pages.
+// The board drives CIRAM's address lines itself, so it keeps the 2K here and
+// answers every nametable access. There is also 128 bytes of internal RAM
+// behind an auto-incrementing port, shared with the sound channels, and a
+// 15-bit IRQ counter that counts CPU cycles up to $7FFF.
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+    ciram: [u8; CIRAM_SIZE],
+    internal_ram: [u8; INTERNAL_RAM_SIZE],
+
+    // Eight pattern table banks followed by four nametable banks
+    chr_banks: [u8; 12],
+    prg_banks: [u8; 3],
+    // Bits 6 and 7 of $E800 keep CIRAM out of the low and high pattern tables
+    ciram_disabled: [bool; 2],
+    // $F800: bit 7 auto-increment, bits 0-6 address
+    ram_address: u8,
+    // $F800 also write-protects PRG-RAM, in 2K pieces
+    prg_ram_protect: u8,
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Namco163 {
+    pub fn new(rom: ROM) -> Self {
+        Namco163 {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            battery: rom.battery,
+            ciram: [0; CIRAM_SIZE],
+            internal_ram: [0; INTERNAL_RAM_SIZE],
+            chr_banks: [0; 12],
+            prg_banks: [0; 3],
+            ciram_disabled: [false; 2],
+            ram_address: 0,
+            prg_ram_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    // Reads or writes the 1K window `slot`, 0-7 for the pattern tables and
+    // 8-11 for the nametables
+    fn chr_location(&self, slot: usize, addr: u16) -> ChrLocation {
+        let bank = self.chr_banks[slot];
+        let ciram_allowed = slot >= 8 || !self.ciram_disabled[slot / 4];
+        let offset = addr as usize & (CHR_BANK_SIZE - 1);
+
+        if bank >= CIRAM_BANKS && ciram_allowed {
+            ChrLocation::Ciram((bank as usize & 1) * CHR_BANK_SIZE + offset)
+        } else {
+            ChrLocation::Chr(bank as usize)
+        }
+    }
+
+    fn internal_ram_access(&mut self) -> usize {
+        let address = (self.ram_address & 0x7f) as usize;
+        if self.ram_address & 0x80 != 0 {
+            self.ram_address = 0x80 | (self.ram_address.wrapping_add(1) & 0x7f);
+        }
+        address
+    }
+
+    // PRG-RAM writes need $4x in the upper nibble of $F800, and each of the
+    // low four bits protects one 2K piece
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let piece = (addr as usize - 0x6000) / 0x0800;
+        self.prg_ram_protect & 0xf0 == 0x40 && self.prg_ram_protect & (1 << piece) == 0
+    }
+}
+
+enum ChrLocation {
+    Chr(usize),
+    Ciram(usize),
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4fff => {
+                let address = self.internal_ram_access();
+                Some(self.internal_ram[address])
+            }
+            _ => self.cpu_peek(addr),
+        }
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        let bank = match addr {
+            0x4800..=0x4fff => {
+                return Some(self.internal_ram[(self.ram_address & 0x7f) as usize]);
+            }
+            0x5000..=0x57ff => return Some(self.irq_counter as u8),
+            0x5800..=0x5fff => {
+                return Some((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7);
+            }
+            0x6000..=0x7fff => return self.prg_ram.read(addr),
+            0x8000..=0xdfff => self.prg_banks[(addr as usize - 0x8000) / PRG_BANK_SIZE] as usize,
+            0xe000..=0xffff => (self.prg_rom.len() / PRG_BANK_SIZE).wrapping_sub(1),
+            _ => return None,
+        };
+
+        Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4fff => {
+                let address = self.internal_ram_access();
+                self.internal_ram[address] = data;
+            }
+            0x5000..=0x57ff => {
+                self.irq_counter = (self.irq_counter & 0x7f00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5fff => {
+                self.irq_counter = (self.irq_counter & 0x00ff) | ((data & 0x7f) as u16) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7fff if self.prg_ram_writable(addr) => self.prg_ram.write(addr, data),
+            0x8000..=0xdfff => self.chr_banks[(addr as usize - 0x8000) / 0x0800] = data,
+            // Bit 6 of $E000 mutes the sound channels, which we don't emulate
+            0xe000..=0xe7ff => self.prg_banks[0] = data & 0x3f,
+            0xe800..=0xefff => {
+                self.prg_banks[1] = data & 0x3f;
+                self.ciram_disabled = [data & 0x40 != 0, data & 0x80 != 0];
+            }
+            0xf000..=0xf7ff => self.prg_banks[2] = data & 0x3f,
+            0xf800..=0xffff => {
+                self.ram_address = data;
+                self.prg_ram_protect = data;
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let slot = addr as usize / CHR_BANK_SIZE;
+        match self.chr_location(slot, addr) {
+            ChrLocation::Chr(bank) => self.chr.read(bank, CHR_BANK_SIZE, addr),
+            ChrLocation::Ciram(offset) => self.ciram[offset],
+        }
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let slot = addr as usize / CHR_BANK_SIZE;
+        match self.chr_location(slot, addr) {
+            ChrLocation::Chr(bank) => self.chr.write(bank, CHR_BANK_SIZE, addr, data),
+            ChrLocation::Ciram(offset) => self.ciram[offset] = data,
+        }
+    }
+
+    // Only used for display, nametable_read answers every access
+    fn mirroring(&self) -> Mirroring {
+        let mut pages = [0; 4];
+        for (nametable, page) in pages.iter_mut().enumerate() {
+            *page = self.chr_banks[8 + nametable] & 1;
+        }
+        Mirroring::Custom(pages)
+    }
+
+    fn nametable_read(&self, addr: u16) -> Option<u8> {
+        let slot = 8 + ((addr as usize >> 10) & 0b11);
+        Some(match self.chr_location(slot, addr) {
+            ChrLocation::Chr(bank) => self.chr.read(bank, CHR_BANK_SIZE, addr),
+            ChrLocation::Ciram(offset) => self.ciram[offset],
+        })
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+        let slot = 8 + ((addr as usize >> 10) & 0b11);
+        match self.chr_location(slot, addr) {
+            // CHR-ROM nametables ignore writes
+            ChrLocation::Chr(bank) => self.chr.write(bank, CHR_BANK_SIZE, addr, data),
+            ChrLocation::Ciram(offset) => self.ciram[offset] = data,
+        }
+        true
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self, cycles: u8) {
+        if !self.irq_enabled {
+            return;
+        }
+
+        // Stops counting once it reaches $7FFF
+        self.irq_counter = (self.irq_counter + cycles as u16).min(IRQ_COUNTER_MAX);
+        if self.irq_counter == IRQ_COUNTER_MAX {
+            self.irq_pending = true;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_bytes(&self.ciram);
+        state.write_bytes(&self.internal_ram);
+        state.write_bytes(&self.chr_banks);
+        state.write_bytes(&self.prg_banks);
+        state.write_bool(self.ciram_disabled[0]);
+        state.write_bool(self.ciram_disabled[1]);
+        state.write_u8(self.ram_address);
+        state.write_u8(self.prg_ram_protect);
+        state.write_u16(self.irq_counter);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        state.read_bytes_into(&mut self.ciram)?;
+        state.read_bytes_into(&mut self.internal_ram)?;
+        state.read_bytes_into(&mut self.chr_banks)?;
+        state.read_bytes_into(&mut self.prg_banks)?;
+        self.ciram_disabled = [state.read_bool()?, state.read_bool()?];
+        self.ram_address = state.read_u8()?;
+        self.prg_ram_protect = state.read_u8()?;
+        self.irq_counter = state.read_u16()? & IRQ_COUNTER_MAX;
+        self.irq_enabled = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        Ok(())
+    }
+
+    // Boards without PRG-RAM keep their saves in the internal RAM
+    fn battery_data(&self) -> Option<&[u8]> {
+        match (self.battery, self.prg_ram.data().is_empty()) {
+            (false, _) => None,
+            (true, false) => Some(self.prg_ram.data()),
+            (true, true) => Some(&self.internal_ram),
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        if self.prg_ram.data().is_empty() {
+            let len = data.len().min(INTERNAL_RAM_SIZE);
+            self.internal_ram[..len].copy_from_slice(&data[..len]);
+        } else {
+            self.prg_ram.load(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_chr_and_nametable_banks_can_show_ciram() {
+        let mut mapper = Namco163::new(test_rom(19, 0, 8, 0x2000, 16, 0x0400));
+        mapper.cpu_write(0x8800, 5);
+        assert_eq!(mapper.ppu_read(0x0400), 5);
+
+        // Nametables from CIRAM page 1 and from CHR-ROM bank 3
+        mapper.cpu_write(0xc000, 0xe1);
+        mapper.cpu_write(0xc800, 3);
+        assert!(mapper.nametable_write(0x2010, 0x77));
+        assert_eq!(mapper.nametable_read(0x2010), Some(0x77));
+        assert_eq!(mapper.nametable_read(0x2410), Some(3));
+
+        // The same CIRAM page mapped as pattern table, unless disabled
+        mapper.cpu_write(0x8000, 0xe1);
+        assert_eq!(mapper.ppu_read(0x0010), 0x77);
+        mapper.cpu_write(0xe800, 0x40);
+        assert_eq!(mapper.ppu_read(0x0010), 0xe1 % 16);
+    }
+
+    #[test]
+    fn test_internal_ram_port_auto_increments() {
+        let mut mapper = Namco163::new(test_rom(19, 0, 8, 0x2000, 16, 0x0400));
+        mapper.cpu_write(0xf800, 0x80 | 0x7f);
+        mapper.cpu_write(0x4800, 0x11);
+        mapper.cpu_write(0x4800, 0x22);
+
+        mapper.cpu_write(0xf800, 0x7f);
+        assert_eq!(mapper.cpu_read(0x4800), Some(0x11));
+        assert_eq!(mapper.cpu_read(0x4800), Some(0x11));
+        mapper.cpu_write(0xf800, 0x00);
+        assert_eq!(mapper.cpu_read(0x4800), Some(0x22));
+    }
+
+    #[test]
+    fn test_prg_banks_and_irq_counter() {
+        let mut mapper = Namco163::new(test_rom(19, 0, 8, 0x2000, 16, 0x0400));
+        mapper.cpu_write(0xe000, 2);
+        mapper.cpu_write(0xf000, 4);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+        assert_eq!(mapper.cpu_read(0xc000), Some(4));
+        assert_eq!(mapper.cpu_read(0xe000), Some(7));
+
+        mapper.cpu_write(0x5000, 0xfd);
+        mapper.cpu_write(0x5800, 0x80 | 0x7f);
+        assert_eq!(mapper.cpu_read(0x5800), Some(0xff));
+        mapper.cpu_clock(1);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(3);
+        assert!(mapper.irq_pending());
+        assert_eq!(mapper.cpu_read(0x5000), Some(0xff));
+
+        mapper.cpu_write(0x5000, 0);
+        assert!(!mapper.irq_pending());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::cartridge::Mirroring;
+use crate::nsf::NsfFile;
+use crate::savestate::{StateReader, StateWriter};
+
+use super::Mapper;
+
+const BANK_SIZE: usize = 0x1000;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// The player returns from INIT and PLAY to this address, which reads as BRK
+// so the CPU hands control back. Nothing in an NSF uses $5FF0.
+pub const PLAYER_RETURN_ADDR: u16 = 0x5ff0;
+
+// NSF music "cartridge": 8K of RAM at $6000 and the tune's code and data at
+// $8000-$FFFF, in eight 4K banks picked by $5FF8-$5FFF when it is banked
+pub struct Nsf {
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    banked: bool,
+    banks: [u8; 8],
+}
+
+impl Nsf {
+    pub fn new(nsf: &NsfFile) -> Self {
+        // Banked tunes are laid out from the start of the 4K bank the load
+        // address falls in, others from $8000
+        let padding = match nsf.banks {
+            Some(_) => nsf.load_addr as usize & (BANK_SIZE - 1),
+            None => nsf.load_addr as usize - 0x8000,
+        };
+        let mut prg = vec![0; padding];
+        prg.extend_from_slice(&nsf.data);
+        let len = prg.len().div_ceil(BANK_SIZE) * BANK_SIZE;
+        prg.resize(len.max(BANK_SIZE), 0);
+
+        Nsf {
+            prg,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            banked: nsf.banks.is_some(),
+            banks: nsf.banks.unwrap_or([0, 1, 2, 3, 4, 5, 6, 7]),
+        }
+    }
+}
+
+impl Mapper for Nsf {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PLAYER_RETURN_ADDR => Some(0x00),
+            0x6000..=0x7fff => Some(self.prg_ram[addr as usize - 0x6000]),
+            0x8000..=0xffff => {
+                let bank = self.banks[(addr as usize - 0x8000) / BANK_SIZE] as usize;
+                let offset = bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1));
+                // Banks past the end of the file read as zeroes
+                Some(self.prg.get(offset).copied().unwrap_or(0))
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5ff8..=0x5fff if self.banked => self.banks[addr as usize - 0x5ff8] = data,
+            0x6000..=0x7fff => self.prg_ram[addr as usize - 0x6000] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.prg_ram);
+        state.write_bytes(&self.banks);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes_into(&mut self.prg_ram)?;
+        state.read_bytes_into(&mut self.banks)
+    }
+}
@@ -0,0 +1,383 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, bus_conflict, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x8000;
+const SECTOR_SIZE: usize = 0x1000;
+
+// SST39SF040 software ID, read back at even and odd addresses
+const MANUFACTURER_ID: u8 = 0xbf;
+const DEVICE_ID: u8 = 0xb7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FlashState {
+    Ready,
+    // $AA written to $5555
+    Unlock1,
+    // $55 written to $2AAA, waiting for the command byte
+    Unlock2,
+    // The next write programs a byte
+    Program,
+}
+
+impl FlashState {
+    fn from_u8(value: u8) -> FlashState {
+        match value {
+            1 => FlashState::Unlock1,
+            2 => FlashState::Unlock2,
+            3 => FlashState::Program,
+            _ => FlashState::Ready,
+        }
+    }
+}
+
+// Mapper 30 (UNROM 512): UxROM-style PRG banking plus four 8K CHR-RAM banks
+// and mapper-picked one-screen mirroring, all in one register. Flashable
+// boards, marked by the battery bit, let the game rewrite its own PRG flash
+// through $8000-$BFFF and move the register to $C000-$FFFF.
+pub struct Unrom512 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    flashable: bool,
+    bus_conflicts: bool,
+    // PRG bank in bits 0-4, CHR bank in bits 5-6, one-screen page in bit 7
+    register: u8,
+
+    flash_state: FlashState,
+    // Set by $80 so the next unlocked command is an erase
+    erase: bool,
+    software_id: bool,
+    modified: bool,
+}
+
+impl Unrom512 {
+    pub fn new(rom: ROM) -> Self {
+        let chr_ram_size = (rom.chr_ram_size + rom.chr_nvram_size).max(CHR_RAM_SIZE);
+
+        Unrom512 {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, chr_ram_size),
+            mirroring: rom.mirroring,
+            flashable: rom.battery,
+            // Flashable boards never have bus conflicts
+            bus_conflicts: !rom.battery && rom.submapper != 1,
+            register: 0,
+            flash_state: FlashState::Ready,
+            erase: false,
+            software_id: false,
+            modified: false,
+        }
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xbfff => (self.register & 0b1_1111) as usize,
+            _ => (self.prg_rom.len() / PRG_BANK_SIZE).wrapping_sub(1),
+        }
+    }
+
+    fn chr_bank(&self) -> usize {
+        ((self.register >> 5) & 0b11) as usize
+    }
+
+    fn flash_write(&mut self, addr: u16, data: u8) {
+        let offset = bank_offset(self.prg_rom.len(), self.prg_bank(addr), PRG_BANK_SIZE, addr);
+        // Commands only look at the low 15 address lines
+        let command_addr = offset & 0x7fff;
+
+        self.flash_state = match (self.flash_state, command_addr, data) {
+            (FlashState::Program, _, _) => {
+                // Programming can only clear bits, erasing sets them again
+                self.prg_rom[offset] &= data;
+                self.modified = true;
+                FlashState::Ready
+            }
+            (_, _, 0xf0) => {
+                self.software_id = false;
+                self.erase = false;
+                FlashState::Ready
+            }
+            (FlashState::Ready, 0x5555, 0xaa) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2aaa, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, _, 0x30) if self.erase => {
+                // Boards with less than a sector of flash erase what's there
+                let sector = offset & !(SECTOR_SIZE - 1);
+                let end = (sector + SECTOR_SIZE).min(self.prg_rom.len());
+                self.prg_rom[sector..end].fill(0xff);
+                self.modified = true;
+                self.erase = false;
+                FlashState::Ready
+            }
+            (FlashState::Unlock2, 0x5555, 0x10) if self.erase => {
+                self.prg_rom.fill(0xff);
+                self.modified = true;
+                self.erase = false;
+                FlashState::Ready
+            }
+            (FlashState::Unlock2, 0x5555, 0x80) => {
+                self.erase = true;
+                FlashState::Ready
+            }
+            (FlashState::Unlock2, 0x5555, 0x90) => {
+                self.software_id = true;
+                FlashState::Ready
+            }
+            (FlashState::Unlock2, 0x5555, 0xa0) => FlashState::Program,
+            _ => {
+                self.erase = false;
+                FlashState::Ready
+            }
+        };
+    }
+}
+
+impl Mapper for Unrom512 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7fff => return self.prg_ram.read(addr),
+            0x8000..=0xffff => {}
+            _ => return None,
+        }
+
+        if self.software_id {
+            return Some(if addr & 1 == 0 {
+                MANUFACTURER_ID
+            } else {
+                DEVICE_ID
+            });
+        }
+
+        let offset = bank_offset(self.prg_rom.len(), self.prg_bank(addr), PRG_BANK_SIZE, addr);
+        Some(self.prg_rom[offset])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xbfff if self.flashable => self.flash_write(addr, data),
+            0x8000..=0xffff => {
+                self.register = if self.bus_conflicts {
+                    bus_conflict(self.cpu_peek(addr), data)
+                } else {
+                    data
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(), CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(self.chr_bank(), CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            Mirroring::SingleScreenLower | Mirroring::SingleScreenUpper => {
+                if self.register & 0x80 != 0 {
+                    Mirroring::SingleScreenUpper
+                } else {
+                    Mirroring::SingleScreenLower
+                }
+            }
+            mirroring => mirroring,
+        }
+    }
+
+    // Four-screen boards keep their nametables in the last 8K of CHR-RAM
+    fn nametable_read(&self, addr: u16) -> Option<u8> {
+        if self.mirroring != Mirroring::FourScreen {
+            return None;
+        }
+        Some(self.chr.read(3, CHR_BANK_SIZE, addr))
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+        if self.mirroring != Mirroring::FourScreen {
+            return false;
+        }
+        self.chr.write(3, CHR_BANK_SIZE, addr, data);
+        true
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.register);
+        if self.flashable {
+            state.write_bytes(&self.prg_rom);
+            state.write_u8(self.flash_state as u8);
+            state.write_bool(self.erase);
+            state.write_bool(self.software_id);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.register = state.read_u8()?;
+        if self.flashable {
+            // Restored flash has to reach the save file too
+            let mut prg_rom = vec![0; self.prg_rom.len()];
+            state.read_bytes_into(&mut prg_rom)?;
+            if prg_rom != self.prg_rom {
+                self.prg_rom = prg_rom;
+                self.modified = true;
+            }
+            self.flash_state = FlashState::from_u8(state.read_u8()?);
+            self.erase = state.read_bool()?;
+            self.software_id = state.read_bool()?;
+        }
+        Ok(())
+    }
+
+    // The whole flash is saved, but only once the game has written to it
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.modified {
+            Some(&self.prg_rom)
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        if self.flashable && data.len() == self.prg_rom.len() {
+            self.prg_rom.copy_from_slice(data);
+            self.modified = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    fn flashable_rom() -> ROM {
+        let mut rom = test_rom(30, 0, 32, 0x4000, 0, 0x2000);
+        rom.battery = true;
+        rom
+    }
+
+    // Writes through the bank register so that `offset` lands in $8000-$BFFF
+    fn flash(mapper: &mut Unrom512, offset: usize, data: u8) {
+        mapper.cpu_write(0xc000, (offset / PRG_BANK_SIZE) as u8);
+        mapper.cpu_write(0x8000 | (offset & (PRG_BANK_SIZE - 1)) as u16, data);
+    }
+
+    fn unlock(mapper: &mut Unrom512, command: u8) {
+        flash(mapper, 0x5555, 0xaa);
+        flash(mapper, 0x2aaa, 0x55);
+        flash(mapper, 0x5555, command);
+    }
+
+    #[test]
+    fn test_register_selects_prg_chr_and_one_screen() {
+        let mut rom = test_rom(30, 1, 32, 0x4000, 0, 0x2000);
+        rom.mirroring = Mirroring::SingleScreenLower;
+        let mut mapper = Unrom512::new(rom);
+        assert_eq!(mapper.cpu_read(0xc000), Some(31));
+
+        mapper.cpu_write(0x8000, 0x80 | 0x40 | 5);
+        assert_eq!(mapper.cpu_read(0x8000), Some(5));
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+
+        mapper.ppu_write(0x0010, 0x42);
+        mapper.cpu_write(0xc000, 0);
+        assert_eq!(mapper.ppu_read(0x0010), 0);
+        mapper.cpu_write(0xc000, 0x40);
+        assert_eq!(mapper.ppu_read(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_four_screen_nametables_live_in_last_chr_bank() {
+        let mut rom = test_rom(30, 1, 2, 0x4000, 0, 0x2000);
+        rom.mirroring = Mirroring::FourScreen;
+        let mut mapper = Unrom512::new(rom);
+        assert!(mapper.nametable_write(0x2c00, 0x99));
+        assert_eq!(mapper.nametable_read(0x2c00), Some(0x99));
+
+        mapper.cpu_write(0x8000, 0x60);
+        assert_eq!(mapper.ppu_read(0x0c00), 0x99);
+    }
+
+    #[test]
+    fn test_flash_program_erase_and_software_id() {
+        let mut mapper = Unrom512::new(flashable_rom());
+        assert!(mapper.battery_data().is_none());
+
+        unlock(&mut mapper, 0x90);
+        assert_eq!(mapper.cpu_read(0x8000), Some(MANUFACTURER_ID));
+        assert_eq!(mapper.cpu_read(0x8001), Some(DEVICE_ID));
+        mapper.cpu_write(0x8000, 0xf0);
+        assert_eq!(mapper.cpu_read(0xc000), Some(31));
+
+        // Programming only clears bits until the sector is erased
+        unlock(&mut mapper, 0xa0);
+        flash(&mut mapper, 0x4_0123, 0x1c);
+        assert_eq!(mapper.battery_data().unwrap()[0x4_0123], 0x10);
+
+        unlock(&mut mapper, 0x80);
+        flash(&mut mapper, 0x5555, 0xaa);
+        flash(&mut mapper, 0x2aaa, 0x55);
+        flash(&mut mapper, 0x4_0000, 0x30);
+        let save = mapper.battery_data().unwrap();
+        assert!(save[0x4_0000..0x4_1000].iter().all(|&byte| byte == 0xff));
+        assert_eq!(save[0x4_1000], 16);
+
+        // A write without the unlock sequence leaves the flash alone
+        flash(&mut mapper, 0x4_0200, 0x00);
+        assert_eq!(mapper.battery_data().unwrap()[0x4_0200], 0xff);
+    }
+
+    #[test]
+    fn test_erasing_a_partial_last_sector() {
+        let mut rom = flashable_rom();
+        rom.prg_rom = vec![0; 0x6800];
+        let mut mapper = Unrom512::new(rom);
+        unlock(&mut mapper, 0x80);
+        flash(&mut mapper, 0x5555, 0xaa);
+        flash(&mut mapper, 0x2aaa, 0x55);
+        flash(&mut mapper, 0x6400, 0x30);
+
+        let save = mapper.battery_data().unwrap();
+        assert!(save[0x6000..].iter().all(|&byte| byte == 0xff));
+        assert_eq!(save[0x5fff], 0);
+    }
+
+    #[test]
+    fn test_loading_state_with_changed_flash_marks_it_for_saving() {
+        let mut mapper = Unrom512::new(flashable_rom());
+        unlock(&mut mapper, 0xa0);
+        flash(&mut mapper, 0x4_0123, 0x00);
+        let mut state = StateWriter::new();
+        mapper.save_state(&mut state);
+        let state = state.finish();
+
+        let mut restored = Unrom512::new(flashable_rom());
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.battery_data().unwrap()[0x4_0123], 0);
+
+        // Restoring the flash as it already is isn't a write
+        let mut state = StateWriter::new();
+        Unrom512::new(flashable_rom()).save_state(&mut state);
+        let state = state.finish();
+        let mut restored = Unrom512::new(flashable_rom());
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert!(restored.battery_data().is_none());
+    }
+}
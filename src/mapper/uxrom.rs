@@ -0,0 +1,114 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, bus_conflict, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_SIZE: usize = 0x2000;
+
+// Mapper 2 (UxROM): switchable 16K bank at $8000, last bank fixed at $C000
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(rom: ROM) -> Self {
+        Uxrom {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            mirroring: rom.mirroring,
+            bus_conflicts: rom.submapper != 1,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        let bank = match addr {
+            0x6000..=0x7fff => return self.prg_ram.read(addr),
+            0x8000..=0xbfff => self.prg_bank as usize,
+            0xc000..=0xffff => (self.prg_rom.len() / PRG_BANK_SIZE).wrapping_sub(1),
+            _ => return None,
+        };
+
+        Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => {
+                self.prg_bank = if self.bus_conflicts {
+                    bus_conflict(self.cpu_peek(addr), data)
+                } else {
+                    data
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(0, CHR_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(0, CHR_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        self.prg_bank = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_switches_bank_at_8000_and_fixes_last_bank() {
+        let mut mapper = Uxrom::new(test_rom(2, 1, 8, 0x4000, 0, 0x2000));
+        assert_eq!(mapper.cpu_read(0x8000), Some(0));
+        assert_eq!(mapper.cpu_read(0xffff), Some(7));
+
+        mapper.cpu_write(0x8000, 5);
+        assert_eq!(mapper.cpu_read(0xbfff), Some(5));
+        assert_eq!(mapper.cpu_read(0xc000), Some(7));
+    }
+
+    #[test]
+    fn test_bus_conflict_ands_with_rom() {
+        // The fixed bank 7 lets 6 through, and bank 6 turns 3 into 2
+        let mut mapper = Uxrom::new(test_rom(2, 2, 8, 0x4000, 0, 0x2000));
+        mapper.cpu_write(0xc000, 6);
+        assert_eq!(mapper.cpu_read(0x8000), Some(6));
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), Some(2));
+    }
+}
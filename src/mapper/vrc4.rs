@@ -0,0 +1,399 @@
+use crate::cartridge::{Mirroring, ROM};
+use crate::chr_mem::ChrMem;
+use crate::mapper::{bank_offset, Mapper};
+use crate::prg_ram::PrgRam;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+// The IRQ prescaler counts down by 3 each CPU cycle, giving one clock every
+// 341 / 3 cycles, which is one scanline
+const PRESCALER_PERIOD: i16 = 341;
+
+// Which CPU address lines the board wires to the chip's two register select
+// pins. Unknown boards get every candidate line ORed together; games only
+// ever use the lines of their own board, so the others stay low.
+fn register_lines(mapper: u16, submapper: u8) -> (u16, u16) {
+    match (mapper, submapper) {
+        (21, 1) => (0x02, 0x04),
+        (21, 2) => (0x40, 0x80),
+        (21, _) => (0x02 | 0x40, 0x04 | 0x80),
+        (22, _) => (0x02, 0x01),
+        (23, 1) | (23, 3) => (0x01, 0x02),
+        (23, 2) => (0x04, 0x08),
+        (23, _) => (0x01 | 0x04, 0x02 | 0x08),
+        (25, 1) | (25, 3) => (0x02, 0x01),
+        (25, 2) => (0x08, 0x04),
+        (_, _) => (0x02 | 0x08, 0x01 | 0x04),
+    }
+}
+
+// Mappers 21, 22, 23 and 25 (Konami VRC2 and VRC4). Both chips bank PRG in
+// 8K and CHR in 1K pages; the VRC4 adds a PRG swap mode, single screen
+// mirroring and an IRQ counter.
+pub struct Vrc4 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: PrgRam,
+    battery: bool,
+    vrc2: bool,
+    // Mapper 23 without a submapper could be VRC2b or VRC4e. It runs as VRC2
+    // until a write on A2/A3, which only VRC4e boards use.
+    detect_vrc4e: bool,
+    // VRC2a only has the upper 7 bits of each CHR bank connected
+    chr_shift: u8,
+    a0_lines: u16,
+    a1_lines: u16,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: u8,
+    // VRC2 boards without RAM have a single bit latch at $6000-$6FFF
+    latch: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_prescaler: i16,
+    irq_enabled: bool,
+    irq_enabled_after_ack: bool,
+    irq_cycle_mode: bool,
+    irq_pending: bool,
+}
+
+impl Vrc4 {
+    pub fn new(rom: ROM) -> Self {
+        let (a0_lines, a1_lines) = register_lines(rom.mapper, rom.submapper);
+        let detect_vrc4e = rom.mapper == 23 && rom.submapper == 0;
+        let vrc2 =
+            detect_vrc4e || matches!((rom.mapper, rom.submapper), (22, _) | (23, 3) | (25, 3));
+
+        Vrc4 {
+            prg_ram: PrgRam::for_rom(&rom),
+            prg_rom: rom.prg_rom,
+            chr: ChrMem::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            battery: rom.battery,
+            vrc2,
+            detect_vrc4e,
+            chr_shift: if rom.mapper == 22 { 1 } else { 0 },
+            a0_lines,
+            a1_lines,
+            prg_banks: [0, 0],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: 0,
+            latch: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_prescaler: PRESCALER_PERIOD,
+            irq_enabled: false,
+            irq_enabled_after_ack: false,
+            irq_cycle_mode: false,
+            irq_pending: false,
+        }
+    }
+
+    // Folds the board's wiring back to $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = addr & self.a0_lines != 0;
+        let a1 = addr & self.a1_lines != 0;
+        (addr & 0xf000) | ((a1 as u16) << 1) | a0 as u16
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        let second_last = (self.prg_rom.len() / PRG_BANK_SIZE).saturating_sub(2);
+        match (addr, self.prg_swap) {
+            (0x8000..=0x9fff, false) | (0xc000..=0xdfff, true) => self.prg_banks[0] as usize,
+            (0x8000..=0x9fff, true) | (0xc000..=0xdfff, false) => second_last,
+            (0xa000..=0xbfff, _) => self.prg_banks[1] as usize,
+            _ => second_last + 1,
+        }
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        (self.chr_banks[addr as usize / CHR_BANK_SIZE] >> self.chr_shift) as usize
+    }
+
+    fn write_chr_bank(&mut self, register: u16, data: u8) {
+        // $B000-$E003 hold the low and high nibble of banks 0-7
+        let index = (((register >> 12) - 0xb) * 2 + ((register >> 1) & 1)) as usize;
+        let bank = &mut self.chr_banks[index];
+
+        if register & 1 == 0 {
+            *bank = (*bank & 0x1f0) | (data & 0x0f) as u16;
+        } else {
+            let high = if self.vrc2 { data & 0x0f } else { data & 0x1f };
+            *bank = (*bank & 0x0f) | ((high as u16) << 4);
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0xff {
+            self.irq_counter = self.irq_latch;
+            self.irq_pending = true;
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        if self.detect_vrc4e && addr & 0x0c != 0 {
+            self.detect_vrc4e = false;
+            self.vrc2 = false;
+            self.a0_lines = 0x04;
+            self.a1_lines = 0x08;
+        }
+
+        let register = self.register(addr);
+        match register {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1f,
+            0x9000..=0x9003 if self.vrc2 => self.mirroring = data & 0b11,
+            0x9000 | 0x9001 => self.mirroring = data & 0b11,
+            0x9002 => self.prg_swap = data & 0b10 != 0,
+            0x9003 => {}
+            0xa000..=0xa003 => self.prg_banks[1] = data & 0x1f,
+            0xb000..=0xe003 => self.write_chr_bank(register, data),
+            _ if self.vrc2 => {}
+            0xf000 => self.irq_latch = (self.irq_latch & 0xf0) | (data & 0x0f),
+            0xf001 => self.irq_latch = (self.irq_latch & 0x0f) | (data << 4),
+            0xf002 => {
+                self.irq_enabled_after_ack = data & 0b001 != 0;
+                self.irq_enabled = data & 0b010 != 0;
+                self.irq_cycle_mode = data & 0b100 != 0;
+                self.irq_pending = false;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_latch;
+                    self.irq_prescaler = PRESCALER_PERIOD;
+                }
+            }
+            _ => {
+                self.irq_pending = false;
+                self.irq_enabled = self.irq_enabled_after_ack;
+            }
+        }
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_peek(addr)
+    }
+
+    fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x6fff if self.vrc2 && self.prg_ram.data().is_empty() => Some(self.latch),
+            0x6000..=0x7fff => self.prg_ram.read(addr),
+            0x8000..=0xffff => {
+                let bank = self.prg_bank(addr);
+                Some(self.prg_rom[bank_offset(self.prg_rom.len(), bank, PRG_BANK_SIZE, addr)])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x6fff if self.vrc2 && self.prg_ram.data().is_empty() => self.latch = data & 1,
+            0x6000..=0x7fff => self.prg_ram.write(addr, data),
+            0x8000..=0xffff => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_bank(addr), CHR_BANK_SIZE, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr
+            .write(self.chr_bank(addr), CHR_BANK_SIZE, addr, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // VRC2 only has the vertical/horizontal bit
+        let mirroring = if self.vrc2 {
+            self.mirroring & 1
+        } else {
+            self.mirroring
+        };
+        match mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self, cycles: u8) {
+        if !self.irq_enabled {
+            return;
+        }
+
+        for _ in 0..cycles {
+            if self.irq_cycle_mode {
+                self.clock_irq_counter();
+                continue;
+            }
+
+            self.irq_prescaler -= 3;
+            if self.irq_prescaler <= 0 {
+                self.irq_prescaler += PRESCALER_PERIOD;
+                self.clock_irq_counter();
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chr.save_state(state);
+        self.prg_ram.save_state(state);
+        state.write_bytes(&self.prg_banks);
+        state.write_bool(self.prg_swap);
+        for bank in self.chr_banks.iter() {
+            state.write_u16(*bank);
+        }
+        state.write_u8(self.mirroring);
+        state.write_u8(self.latch);
+        state.write_u8(self.irq_latch);
+        state.write_u8(self.irq_counter);
+        state.write_u16(self.irq_prescaler as u16);
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.irq_enabled_after_ack);
+        state.write_bool(self.irq_cycle_mode);
+        state.write_bool(self.irq_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.chr.load_state(state)?;
+        self.prg_ram.load_state(state)?;
+        state.read_bytes_into(&mut self.prg_banks)?;
+        self.prg_swap = state.read_bool()?;
+        for bank in self.chr_banks.iter_mut() {
+            *bank = state.read_u16()?;
+        }
+        self.mirroring = state.read_u8()? & 0b11;
+        self.latch = state.read_u8()? & 1;
+        self.irq_latch = state.read_u8()?;
+        self.irq_counter = state.read_u8()?;
+        self.irq_prescaler = (state.read_u16()? as i16).clamp(1, PRESCALER_PERIOD);
+        self.irq_enabled = state.read_bool()?;
+        self.irq_enabled_after_ack = state.read_bool()?;
+        self.irq_cycle_mode = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        Ok(())
+    }
+
+    fn battery_data(&self) -> Option<&[u8]> {
+        if self.battery {
+            Some(self.prg_ram.data())
+        } else {
+            None
+        }
+    }
+
+    fn load_battery_data(&mut self, data: &[u8]) {
+        self.prg_ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_register_lines_per_board() {
+        let boards = [
+            (21, 1, 0x02, 0x04),
+            (21, 2, 0x40, 0x80),
+            (22, 0, 0x02, 0x01),
+            (23, 1, 0x01, 0x02),
+            (23, 2, 0x04, 0x08),
+            (23, 3, 0x01, 0x02),
+            (25, 1, 0x02, 0x01),
+            (25, 2, 0x08, 0x04),
+            (25, 3, 0x02, 0x01),
+        ];
+
+        for (mapper_number, submapper, a0, a1) in boards {
+            let rom = test_rom(mapper_number, submapper, 16, 0x2000, 64, 0x0400);
+            let mut mapper = Vrc4::new(rom);
+            // Low then high nibble of CHR bank 1 are $B002 and $B003
+            mapper.cpu_write(0xb000 | a1, 0x0a);
+            mapper.cpu_write(0xb000 | a0 | a1, 0x01);
+            // VRC2a drops the lowest bank bit
+            let expected = if mapper_number == 22 { 0x0d } else { 0x1a };
+            assert_eq!(
+                mapper.ppu_read(0x0400),
+                expected,
+                "mapper {} submapper {}",
+                mapper_number,
+                submapper
+            );
+        }
+    }
+
+    #[test]
+    fn test_prg_rom_under_16k_mirrors() {
+        let mut rom = test_rom(21, 1, 2, 0x2000, 8, 0x0400);
+        rom.prg_rom = vec![0x42; 0x2000];
+        let mut mapper = Vrc4::new(rom);
+        assert_eq!(mapper.cpu_read(0xc000), Some(0x42));
+        assert_eq!(mapper.cpu_read(0xe000), Some(0x42));
+    }
+
+    #[test]
+    fn test_unknown_mapper_23_is_vrc2b_until_a2_a3_writes() {
+        let mut mapper = Vrc4::new(test_rom(23, 0, 16, 0x2000, 64, 0x0400));
+        mapper.cpu_write(0x6000, 1);
+        assert_eq!(mapper.cpu_read(0x6000), Some(1));
+        mapper.cpu_write(0x9000, 0b11);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+
+        // A VRC4e game gives itself away through $9004, its $9001
+        mapper.cpu_write(0x9004, 0b11);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+        mapper.cpu_write(0xb008, 0x0a);
+        assert_eq!(mapper.ppu_read(0x0400), 0x0a);
+    }
+
+    #[test]
+    fn test_irq_prescaler_clocks_once_per_scanline() {
+        let mut mapper = Vrc4::new(test_rom(23, 1, 16, 0x2000, 8, 0x0400));
+        mapper.cpu_write(0xf000, 0x0e);
+        mapper.cpu_write(0xf001, 0x0f);
+        mapper.cpu_write(0xf002, 0b010);
+
+        // 341 / 3 cycles per clock, with the remainder carried over
+        mapper.cpu_clock(113);
+        assert_eq!(mapper.irq_counter, 0xfe);
+        mapper.cpu_clock(1);
+        assert_eq!(mapper.irq_counter, 0xff);
+        mapper.cpu_clock(113);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(1);
+        assert!(mapper.irq_pending());
+        assert_eq!(mapper.irq_counter, 0xfe);
+
+        // Acknowledging copies the enable-after-ack bit back, which is off
+        mapper.cpu_write(0xf003, 0);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(0xff);
+        assert_eq!(mapper.irq_counter, 0xfe);
+    }
+
+    #[test]
+    fn test_irq_cycle_mode_skips_prescaler() {
+        let mut mapper = Vrc4::new(test_rom(23, 1, 16, 0x2000, 8, 0x0400));
+        mapper.cpu_write(0xf000, 0x0d);
+        mapper.cpu_write(0xf001, 0x0f);
+        mapper.cpu_write(0xf002, 0b110);
+        mapper.cpu_clock(2);
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock(1);
+        assert!(mapper.irq_pending());
+    }
+}
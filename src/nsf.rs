@@ -0,0 +1,584 @@
+// NSF and NSFe music files. The tune's own INIT and PLAY routines run on the
+// CPU against a bus with the NSF mapper in place of a cartridge.
+
+use crate::bus::Bus;
+use crate::cartridge::Timing;
+use crate::cpu::{Mem, CPU};
+use crate::mapper::{Nsf, PLAYER_RETURN_ADDR};
+
+const NSF_TAG: &[u8] = b"NESM\x1a";
+const NSFE_TAG: &[u8] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+const NTSC_CPU_HZ: u64 = 1_789_773;
+const PAL_CPU_HZ: u64 = 1_662_607;
+// Play rates NSFe assumes without a RATE chunk, in microseconds
+const NTSC_PLAY_SPEED: u16 = 16639;
+const PAL_PLAY_SPEED: u16 = 19997;
+
+// Enough for INIT routines that decompress data, while still catching tunes
+// that never return
+const MAX_ROUTINE_INSTRUCTIONS: usize = 2_000_000;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    pub length_ms: Option<u32>,
+    pub fade_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NsfFile {
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub total_songs: u8,
+    // Zero based
+    pub starting_song: u8,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    // PLAY call periods in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    pub timing: Timing,
+    // Bitmask of expansion sound chips: VRC6, VRC7, FDS, MMC5, N163, 5B
+    pub expansion_audio: u8,
+    // Initial $5FF8-$5FFF values, None for tunes that aren't bankswitched
+    pub banks: Option<[u8; 8]>,
+    pub data: Vec<u8>,
+    // One entry per song, filled from NSFe metadata when there is any
+    pub tracks: Vec<TrackInfo>,
+    // Song order suggested by the NSFe, empty when there is none
+    pub playlist: Vec<u8>,
+}
+
+pub fn is_nsf(raw: &[u8]) -> bool {
+    raw.starts_with(NSF_TAG) || raw.starts_with(NSFE_TAG)
+}
+
+impl NsfFile {
+    pub fn parse(raw: &[u8]) -> Result<NsfFile, String> {
+        if raw.starts_with(NSFE_TAG) {
+            parse_nsfe(&raw[NSFE_TAG.len()..])
+        } else if raw.starts_with(NSF_TAG) {
+            parse_nsf(raw)
+        } else {
+            Err("File is not in NSF file format".to_string())
+        }
+    }
+
+    // CPU cycles between PLAY calls
+    pub fn play_period_cycles(&self) -> u32 {
+        let (speed, default_speed, hz) = match self.timing {
+            Timing::Pal | Timing::Dendy => (self.pal_speed, PAL_PLAY_SPEED, PAL_CPU_HZ),
+            _ => (self.ntsc_speed, NTSC_PLAY_SPEED, NTSC_CPU_HZ),
+        };
+        // Older rips leave the rate for the region they don't target at 0
+        let speed = if speed == 0 { default_speed } else { speed };
+        ((speed as u64 * hz + 500_000) / 1_000_000) as u32
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.load_addr < 0x8000 {
+            return Err(format!(
+                "Load address ${:04X} is below $8000",
+                self.load_addr
+            ));
+        }
+        if self.total_songs == 0 {
+            return Err("NSF has no songs".to_string());
+        }
+        if self.starting_song >= self.total_songs {
+            return Err(format!(
+                "Starting song {} is out of range, the file has {}",
+                self.starting_song as u16 + 1,
+                self.total_songs
+            ));
+        }
+        if self.data.is_empty() {
+            return Err("NSF has no program data".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn parse_nsf(raw: &[u8]) -> Result<NsfFile, String> {
+    if raw.len() < NSF_HEADER_SIZE {
+        return Err("NSF header is truncated".to_string());
+    }
+
+    let word = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+    let banks = &raw[0x70..0x78];
+    let timing = match raw[0x7a] & 0b11 {
+        0 => Timing::Ntsc,
+        1 => Timing::Pal,
+        _ => Timing::MultiRegion,
+    };
+
+    // NSF2 can give the data length, with NSFe metadata chunks after it
+    let data = &raw[NSF_HEADER_SIZE..];
+    let data_len = u32::from_le_bytes([raw[0x7d], raw[0x7e], raw[0x7f], 0]) as usize;
+    let (data, metadata) = if raw[5] >= 2 && data_len > 0 && data_len <= data.len() {
+        data.split_at(data_len)
+    } else {
+        (data, &[][..])
+    };
+
+    let total_songs = raw[6];
+    let mut nsf = NsfFile {
+        load_addr: word(0x08),
+        init_addr: word(0x0a),
+        play_addr: word(0x0c),
+        total_songs,
+        starting_song: raw[7].saturating_sub(1),
+        title: header_string(&raw[0x0e..0x2e]),
+        artist: header_string(&raw[0x2e..0x4e]),
+        copyright: header_string(&raw[0x4e..0x6e]),
+        ripper: String::new(),
+        ntsc_speed: word(0x6e),
+        pal_speed: word(0x78),
+        timing,
+        expansion_audio: raw[0x7b],
+        banks: if banks.iter().any(|&bank| bank != 0) {
+            let mut initial = [0; 8];
+            initial.copy_from_slice(banks);
+            Some(initial)
+        } else {
+            None
+        },
+        data: data.to_vec(),
+        tracks: vec![TrackInfo::default(); total_songs as usize],
+        playlist: vec![],
+    };
+
+    for chunk in chunks(metadata)? {
+        apply_metadata_chunk(&mut nsf, chunk)?;
+    }
+
+    nsf.validate()?;
+    Ok(nsf)
+}
+
+fn parse_nsfe(raw: &[u8]) -> Result<NsfFile, String> {
+    let mut nsf = NsfFile {
+        load_addr: 0,
+        init_addr: 0,
+        play_addr: 0,
+        total_songs: 1,
+        starting_song: 0,
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        ripper: String::new(),
+        ntsc_speed: NTSC_PLAY_SPEED,
+        pal_speed: PAL_PLAY_SPEED,
+        timing: Timing::Ntsc,
+        expansion_audio: 0,
+        banks: None,
+        data: vec![],
+        tracks: vec![],
+        playlist: vec![],
+    };
+    let mut has_info = false;
+
+    for chunk in chunks(raw)? {
+        match &chunk.id {
+            b"INFO" => {
+                let data = chunk.data;
+                if data.len() < 9 {
+                    return Err("NSFe INFO chunk is truncated".to_string());
+                }
+                nsf.load_addr = u16::from_le_bytes([data[0], data[1]]);
+                nsf.init_addr = u16::from_le_bytes([data[2], data[3]]);
+                nsf.play_addr = u16::from_le_bytes([data[4], data[5]]);
+                nsf.timing = match data[6] & 0b11 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    _ => Timing::MultiRegion,
+                };
+                nsf.expansion_audio = data[7];
+                nsf.total_songs = data[8];
+                nsf.starting_song = data.get(9).copied().unwrap_or(0);
+                has_info = true;
+            }
+            b"DATA" => nsf.data = chunk.data.to_vec(),
+            b"BANK" => {
+                let mut banks = [0; 8];
+                for (bank, &value) in banks.iter_mut().zip(chunk.data) {
+                    *bank = value;
+                }
+                nsf.banks = Some(banks);
+            }
+            _ => apply_metadata_chunk(&mut nsf, chunk)?,
+        }
+    }
+
+    if !has_info {
+        return Err("NSFe file has no INFO chunk".to_string());
+    }
+    nsf.tracks
+        .resize(nsf.total_songs as usize, TrackInfo::default());
+    nsf.validate()?;
+    Ok(nsf)
+}
+
+struct Chunk<'a> {
+    id: [u8; 4],
+    data: &'a [u8],
+}
+
+// NSFe chunks: a little endian length, a four letter ID, then the data. NEND
+// marks the end.
+fn chunks(mut raw: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut chunks = vec![];
+    while raw.len() >= 8 {
+        let len = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize;
+        let mut id = [0; 4];
+        id.copy_from_slice(&raw[4..8]);
+        raw = &raw[8..];
+
+        if &id == b"NEND" {
+            return Ok(chunks);
+        }
+        if len > raw.len() {
+            return Err(format!(
+                "NSFe chunk {} is truncated",
+                String::from_utf8_lossy(&id)
+            ));
+        }
+        chunks.push(Chunk {
+            id,
+            data: &raw[..len],
+        });
+        raw = &raw[len..];
+    }
+    Ok(chunks)
+}
+
+// Chunks that only describe the music. Ones starting with a capital letter
+// must be understood to play the file, the rest are optional.
+fn apply_metadata_chunk(nsf: &mut NsfFile, chunk: Chunk) -> Result<(), String> {
+    let track_count = nsf.total_songs as usize;
+    let tracks = &mut nsf.tracks;
+    if tracks.len() < track_count {
+        tracks.resize(track_count, TrackInfo::default());
+    }
+
+    match &chunk.id {
+        b"RATE" => {
+            let data = chunk.data;
+            if data.len() >= 2 {
+                nsf.ntsc_speed = u16::from_le_bytes([data[0], data[1]]);
+            }
+            if data.len() >= 4 {
+                nsf.pal_speed = u16::from_le_bytes([data[2], data[3]]);
+            }
+        }
+        b"auth" => {
+            let mut strings = chunk_strings(chunk.data).into_iter();
+            nsf.title = strings.next().unwrap_or_default();
+            nsf.artist = strings.next().unwrap_or_default();
+            nsf.copyright = strings.next().unwrap_or_default();
+            nsf.ripper = strings.next().unwrap_or_default();
+        }
+        b"tlbl" => {
+            for (track, name) in tracks.iter_mut().zip(chunk_strings(chunk.data)) {
+                track.name = Some(name);
+            }
+        }
+        b"time" => {
+            for (track, ms) in tracks.iter_mut().zip(chunk_times(chunk.data)) {
+                track.length_ms = ms;
+            }
+        }
+        b"fade" => {
+            for (track, ms) in tracks.iter_mut().zip(chunk_times(chunk.data)) {
+                track.fade_ms = ms;
+            }
+        }
+        b"plst" => nsf.playlist = chunk.data.to_vec(),
+        id if id[0].is_ascii_uppercase() => {
+            return Err(format!(
+                "NSFe chunk {} is not supported",
+                String::from_utf8_lossy(id)
+            ));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn header_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn chunk_strings(data: &[u8]) -> Vec<String> {
+    let data = data.strip_suffix(&[0]).unwrap_or(data);
+    if data.is_empty() {
+        return vec![];
+    }
+    data.split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+// Signed milliseconds per track, negative meaning the player's default
+fn chunk_times(data: &[u8]) -> Vec<Option<u32>> {
+    data.chunks_exact(4)
+        .map(|c| {
+            let ms = i32::from_le_bytes([c[0], c[1], c[2], c[3]]);
+            if ms < 0 {
+                None
+            } else {
+                Some(ms as u32)
+            }
+        })
+        .collect()
+}
+
+// Runs a tune: INIT once per track, then PLAY at the rate the file asks for
+pub struct NsfPlayer {
+    pub cpu: CPU,
+    nsf: NsfFile,
+    track: u8,
+    period_cycles: u32,
+    pending_cycles: u32,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: NsfFile) -> Self {
+        let bus = Bus::new(Box::new(Nsf::new(&nsf)));
+        NsfPlayer {
+            cpu: CPU::new(bus),
+            period_cycles: nsf.play_period_cycles(),
+            track: nsf.starting_song,
+            nsf,
+            pending_cycles: 0,
+        }
+    }
+
+    pub fn nsf(&self) -> &NsfFile {
+        &self.nsf
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    pub fn track_info(&self) -> &TrackInfo {
+        &self.nsf.tracks[self.track as usize]
+    }
+
+    // Resets the machine and runs INIT for a zero based track number
+    pub fn start_track(&mut self, track: u8) -> Result<(), String> {
+        if track >= self.nsf.total_songs {
+            return Err(format!(
+                "Track {} is out of range, the file has {}",
+                track + 1,
+                self.nsf.total_songs
+            ));
+        }
+        self.track = track;
+        self.pending_cycles = 0;
+
+        for addr in 0x0000..0x0800 {
+            self.cpu.mem_write(addr, 0);
+        }
+        for addr in 0x6000..0x8000 {
+            self.cpu.mem_write(addr, 0);
+        }
+        // Silence every channel and put the frame counter in 4-step mode
+        // with its IRQ off
+        for addr in 0x4000..0x4014 {
+            self.cpu.mem_write(addr, 0);
+        }
+        self.cpu.mem_write(0x4015, 0x00);
+        self.cpu.mem_write(0x4015, 0x0f);
+        self.cpu.mem_write(0x4017, 0x40);
+
+        if let Some(banks) = self.nsf.banks {
+            for (addr, &bank) in (0x5ff8..).zip(banks.iter()) {
+                self.cpu.mem_write(addr, bank);
+            }
+        }
+
+        self.cpu.register_a = track;
+        self.cpu.register_x = match self.nsf.timing {
+            Timing::Pal | Timing::Dendy => 1,
+            _ => 0,
+        };
+        self.cpu.register_y = 0;
+        self.call(self.nsf.init_addr)
+    }
+
+    pub fn play(&mut self) -> Result<(), String> {
+        self.call(self.nsf.play_addr)
+    }
+
+    // Advances the player clock, calling PLAY once for each period that
+    // passes
+    pub fn run_cycles(&mut self, cycles: u32) -> Result<(), String> {
+        self.pending_cycles += cycles;
+        while self.pending_cycles >= self.period_cycles {
+            self.pending_cycles -= self.period_cycles;
+            self.play()?;
+        }
+        Ok(())
+    }
+
+    // JSRs to `addr` and runs until it returns to the BRK at
+    // PLAYER_RETURN_ADDR
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+        self.cpu.stack_pointer = 0xfd;
+        self.cpu.stack_push_u16(PLAYER_RETURN_ADDR - 1);
+        self.cpu.program_counter = addr;
+
+        let mut instructions = 0;
+        let mut returned = true;
+        self.cpu.run_with_callback(|cpu| {
+            instructions += 1;
+            if instructions >= MAX_ROUTINE_INSTRUCTIONS {
+                returned = false;
+                cpu.program_counter = PLAYER_RETURN_ADDR;
+            }
+        });
+
+        if returned {
+            Ok(())
+        } else {
+            Err(format!("Routine at ${:04X} did not return", addr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // INIT stores the track number at $00, PLAY counts calls at $01
+    const PROGRAM: &[u8] = &[
+        0x85, 0x00, // $8000: STA $00
+        0x60, // RTS
+        0xe6, 0x01, // $8003: INC $01
+        0x60, // RTS
+    ];
+
+    fn nsf_file(program: &[u8], banks: [u8; 8]) -> Vec<u8> {
+        let mut raw = NSF_TAG.to_vec();
+        raw.extend_from_slice(&[1, 3, 2]);
+        raw.extend_from_slice(&0x8000u16.to_le_bytes());
+        raw.extend_from_slice(&0x8000u16.to_le_bytes());
+        raw.extend_from_slice(&0x8003u16.to_le_bytes());
+        let mut title = b"Title".to_vec();
+        title.resize(32, 0);
+        raw.extend_from_slice(&title);
+        raw.resize(0x6e, 0);
+        raw.extend_from_slice(&NTSC_PLAY_SPEED.to_le_bytes());
+        raw.extend_from_slice(&banks);
+        raw.resize(NSF_HEADER_SIZE, 0);
+        raw.extend_from_slice(program);
+        raw
+    }
+
+    #[test]
+    fn test_calls_init_and_play_at_the_declared_rate() {
+        let nsf = NsfFile::parse(&nsf_file(PROGRAM, [0; 8])).unwrap();
+        assert_eq!(nsf.title, "Title");
+        assert_eq!((nsf.total_songs, nsf.starting_song), (3, 1));
+        assert_eq!(nsf.play_period_cycles(), 29780);
+
+        let mut player = NsfPlayer::new(nsf);
+        player.start_track(2).unwrap();
+        assert_eq!(player.cpu.bus.peek(0x00), Some(2));
+
+        player.run_cycles(29780 * 3 + 100).unwrap();
+        assert_eq!(player.cpu.bus.peek(0x01), Some(3));
+        assert!(player.start_track(3).is_err());
+    }
+
+    #[test]
+    fn test_rejects_starting_song_past_the_last() {
+        let mut raw = nsf_file(PROGRAM, [0; 8]);
+        raw[7] = 3;
+        assert_eq!(NsfFile::parse(&raw).unwrap().starting_song, 2);
+        raw[7] = 4;
+        assert!(NsfFile::parse(&raw).is_err());
+    }
+
+    #[test]
+    fn test_bankswitches_4k_windows() {
+        // Bank 0 has the program, bank 1 is filled with $11
+        let mut program = PROGRAM.to_vec();
+        program.resize(0x1000, 0);
+        program.extend_from_slice(&[0x11; 0x1000]);
+        let mut player =
+            NsfPlayer::new(NsfFile::parse(&nsf_file(&program, [0, 1, 0, 0, 0, 0, 0, 0])).unwrap());
+        player.start_track(0).unwrap();
+
+        assert_eq!(player.cpu.bus.peek(0x9000), Some(0x11));
+        player.cpu.mem_write(0x5fff, 1);
+        assert_eq!(player.cpu.bus.peek(0xf000), Some(0x11));
+        player.cpu.mem_write(0x5ff8, 1);
+        assert_eq!(player.cpu.bus.peek(0x8000), Some(0x11));
+    }
+
+    #[test]
+    fn test_parses_nsfe_metadata() {
+        fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+            chunk.extend_from_slice(id);
+            chunk.extend_from_slice(data);
+            chunk
+        }
+
+        let mut info = vec![0x00, 0x80, 0x00, 0x80, 0x03, 0x80, 0, 0, 2, 1];
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(b"INFO", &info));
+        raw.extend(chunk(b"DATA", PROGRAM));
+        raw.extend(chunk(b"auth", b"Game\0Composer\0\0Ripper\0"));
+        raw.extend(chunk(b"tlbl", b"Intro\0Boss\0"));
+        raw.extend(chunk(
+            b"time",
+            &[1000i32.to_le_bytes(), (-1i32).to_le_bytes()].concat(),
+        ));
+        raw.extend(chunk(b"fade", &500i32.to_le_bytes()));
+        raw.extend(chunk(b"NEND", &[]));
+
+        let nsf = NsfFile::parse(&raw).unwrap();
+        assert_eq!((nsf.total_songs, nsf.starting_song), (2, 1));
+        assert_eq!(nsf.title, "Game");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.copyright, "");
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(
+            nsf.tracks,
+            vec![
+                TrackInfo {
+                    name: Some("Intro".to_string()),
+                    length_ms: Some(1000),
+                    fade_ms: Some(500),
+                },
+                TrackInfo {
+                    name: Some("Boss".to_string()),
+                    length_ms: None,
+                    fade_ms: None,
+                },
+            ]
+        );
+        assert_eq!(nsf.play_period_cycles(), 29780);
+
+        // Chunks a player must understand can't be skipped
+        info[8] = 1;
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(b"INFO", &info));
+        raw.extend(chunk(b"DATA", PROGRAM));
+        raw.extend(chunk(b"VRC7", &[0]));
+        assert_eq!(
+            NsfFile::parse(&raw).err().unwrap(),
+            "NSFe chunk VRC7 is not supported"
+        );
+    }
+}
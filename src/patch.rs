@@ -0,0 +1,465 @@
+use std::path::{Path, PathBuf};
+
+use crate::hash::Crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const UPS_MAGIC: &[u8] = b"UPS1";
+// BPS and UPS both end with CRC32s of the source, the target and the patch
+const FOOTER_SIZE: usize = 12;
+// Far beyond any real image, only here to reject nonsense sizes
+const MAX_TARGET_SIZE: usize = 0x400_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+    Ups,
+}
+
+impl PatchFormat {
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchFormat::Bps)
+        } else if patch.starts_with(UPS_MAGIC) {
+            Some(PatchFormat::Ups)
+        } else {
+            None
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            PatchFormat::Ips => "ips",
+            PatchFormat::Bps => "bps",
+            PatchFormat::Ups => "ups",
+        }
+    }
+}
+
+// A patch sitting next to the ROM with the same name, e.g. game.ips for
+// game.nes
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    [PatchFormat::Ips, PatchFormat::Bps, PatchFormat::Ups]
+        .iter()
+        .map(|format| rom_path.with_extension(format.extension()))
+        .find(|path| path.is_file())
+}
+
+pub fn apply_file(source: &[u8], patch_path: &Path) -> Result<Vec<u8>, String> {
+    let patch = std::fs::read(patch_path)
+        .map_err(|err| format!("Can't read {}: {}", patch_path.display(), err))?;
+    apply(source, &patch).map_err(|err| format!("{}: {}", patch_path.display(), err))
+}
+
+// Applies a patch to the raw file, header included, so the result can go
+// straight to ROM::new
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(source, patch),
+        Some(PatchFormat::Bps) => apply_bps(source, patch),
+        Some(PatchFormat::Ups) => apply_ups(source, patch),
+        None => Err("Unknown patch format".to_string()),
+    }
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        PatchReader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len);
+        let bytes = end
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| "Patch is truncated".to_string())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, &byte| (value << 8) | byte as usize))
+    }
+
+    // BPS/UPS numbers: 7 bits per byte, low bits first, with the top bit
+    // marking the last byte. Each continuation also adds one so that every
+    // number has a single encoding.
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            value = (byte as usize & 0x7f)
+                .checked_mul(shift)
+                .and_then(|add| value.checked_add(add))
+                .ok_or_else(|| "Patch number is too large".to_string())?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_shl(7)
+                .ok_or_else(|| "Patch number is too large".to_string())?;
+            value = value
+                .checked_add(shift)
+                .ok_or_else(|| "Patch number is too large".to_string())?;
+        }
+    }
+
+    fn signed_varint(&mut self) -> Result<isize, String> {
+        let value = self.varint()?;
+        let magnitude = (value >> 1) as isize;
+        Ok(if value & 1 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+}
+
+// Records of 3 byte offset and 2 byte length, where a length of 0 is a run of
+// one repeated byte. The EOF marker can be followed by a 3 byte size to
+// truncate the file to.
+fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut output = source.to_vec();
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        if reader.bytes(3)? == IPS_EOF {
+            break;
+        }
+        reader.pos -= 3;
+
+        let offset = reader.be(3)?;
+        let len = reader.be(2)?;
+        let (len, data) = if len == 0 {
+            let len = reader.be(2)?;
+            (len, vec![reader.u8()?; len])
+        } else {
+            (len, reader.bytes(len)?.to_vec())
+        };
+
+        if output.len() < offset + len {
+            output.resize(offset + len, 0);
+        }
+        output[offset..offset + len].copy_from_slice(&data);
+    }
+
+    if let Ok(size) = reader.be(3) {
+        output.truncate(size);
+    }
+
+    Ok(output)
+}
+
+fn check_crc(data: &[u8], expected: &[u8], what: &str) -> Result<(), String> {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    let expected = u32::from_le_bytes([expected[0], expected[1], expected[2], expected[3]]);
+    if crc.finish() != expected {
+        return Err(format!(
+            "{} CRC32 mismatch: expected {:08x}, found {:08x}",
+            what,
+            expected,
+            crc.finish()
+        ));
+    }
+    Ok(())
+}
+
+fn split_footer(patch: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if patch.len() < FOOTER_SIZE + 4 {
+        return Err("Patch is truncated".to_string());
+    }
+
+    let (body, footer) = patch.split_at(patch.len() - FOOTER_SIZE);
+    check_crc(&patch[..patch.len() - 4], &footer[8..], "Patch")?;
+    Ok((body, footer))
+}
+
+fn check_sizes(source: &[u8], source_size: usize, target_size: usize) -> Result<(), String> {
+    if source_size != source.len() {
+        return Err(format!(
+            "Patch is for a {} byte file, not {} bytes",
+            source_size,
+            source.len()
+        ));
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(format!("Patch target size {} is too large", target_size));
+    }
+    Ok(())
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (body, footer) = split_footer(patch)?;
+    check_crc(source, &footer[..4], "Source")?;
+
+    let mut reader = PatchReader::new(body, BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    check_sizes(source, source_size, target_size)?;
+
+    let mut output: Vec<u8> = vec![];
+    let mut source_pos: isize = 0;
+    let mut target_pos: isize = 0;
+    let out_of_range = || "Patch reads outside the file".to_string();
+
+    while reader.pos < body.len() {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        // Checked up front so a bad length can't run away with memory
+        if len > target_size - output.len() {
+            return Err("Patch writes past the end of the target".to_string());
+        }
+
+        match action & 0b11 {
+            // SourceRead: copy from the same offset in the source
+            0 => {
+                let start = output.len();
+                let data = source.get(start..start + len).ok_or_else(out_of_range)?;
+                output.extend_from_slice(data);
+            }
+            // TargetRead: literal bytes from the patch
+            1 => output.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy: copy from anywhere in the source
+            2 => {
+                source_pos = source_pos
+                    .checked_add(reader.signed_varint()?)
+                    .ok_or_else(out_of_range)?;
+                let start = usize::try_from(source_pos).map_err(|_| out_of_range())?;
+                let data = source.get(start..start + len).ok_or_else(out_of_range)?;
+                output.extend_from_slice(data);
+                source_pos += len as isize;
+            }
+            // TargetCopy: copy from earlier output, one byte at a time since
+            // the ranges may overlap
+            _ => {
+                target_pos = target_pos
+                    .checked_add(reader.signed_varint()?)
+                    .ok_or_else(out_of_range)?;
+                for _ in 0..len {
+                    let byte = usize::try_from(target_pos)
+                        .ok()
+                        .and_then(|pos| output.get(pos).copied())
+                        .ok_or_else(out_of_range)?;
+                    output.push(byte);
+                    target_pos += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(format!(
+            "Patch produced {} bytes instead of {}",
+            output.len(),
+            target_size
+        ));
+    }
+    check_crc(&output, &footer[4..8], "Target")?;
+
+    Ok(output)
+}
+
+// Hunks of a relative skip followed by bytes XORed into the file up to and
+// including a 0 byte
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (body, footer) = split_footer(patch)?;
+    check_crc(source, &footer[..4], "Source")?;
+
+    let mut reader = PatchReader::new(body, UPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+
+    check_sizes(source, source_size, target_size)?;
+
+    let mut output = source.to_vec();
+    output.resize(target_size, 0);
+
+    let mut pos: usize = 0;
+    while reader.pos < body.len() {
+        pos = pos
+            .checked_add(reader.varint()?)
+            .ok_or_else(|| "Patch is truncated".to_string())?;
+        loop {
+            let byte = reader.u8()?;
+            if let Some(out) = output.get_mut(pos) {
+                *out ^= byte;
+            }
+            pos = pos.saturating_add(1);
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    check_crc(&output, &footer[4..8], "Target")?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut value: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte | 0x80);
+                return bytes;
+            }
+            bytes.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn crc32(data: &[u8]) -> [u8; 4] {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish().to_le_bytes()
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source));
+        patch.extend_from_slice(&crc32(target));
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc);
+        patch
+    }
+
+    #[test]
+    fn test_ips_records_rle_and_truncation() {
+        let source = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 1, then a run of four 7s at 4 that grows the file
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 7]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply(&source, &patch).unwrap(),
+            vec![0, 0xaa, 0xbb, 0, 0, 0, 7, 7, 7, 7]
+        );
+
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply(&source, &patch).unwrap(), vec![0, 0xaa, 0xbb]);
+    }
+
+    #[test]
+    fn test_bps_actions_and_crc_checks() {
+        let source = b"abcdef".to_vec();
+        let target = b"abXYcdcdcd".to_vec();
+
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(0));
+        // SourceRead "ab", TargetRead "XY", SourceCopy "cd" from offset 2,
+        // TargetCopy 4 bytes from offset 4 overlapping itself
+        patch.extend(varint(1 << 2));
+        patch.extend(varint((1 << 2) | 1));
+        patch.extend_from_slice(b"XY");
+        patch.extend(varint((1 << 2) | 2));
+        patch.extend(varint(2 << 1));
+        patch.extend(varint((3 << 2) | 3));
+        patch.extend(varint(4 << 1));
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+        assert!(apply(b"abcdeF", &patch)
+            .unwrap_err()
+            .starts_with("Source CRC32 mismatch"));
+
+        let mut corrupt = patch.clone();
+        corrupt[5] ^= 1;
+        assert!(apply(&source, &corrupt)
+            .unwrap_err()
+            .starts_with("Patch CRC32 mismatch"));
+    }
+
+    #[test]
+    fn test_bps_rejects_actions_past_target_size() {
+        let source = b"abcdef".to_vec();
+        let target = b"aaaa".to_vec();
+        let build = |action: usize, offset: usize| {
+            let mut patch = b"BPS1".to_vec();
+            patch.extend(varint(source.len()));
+            patch.extend(varint(target.len()));
+            patch.extend(varint(0));
+            patch.extend(varint(1));
+            patch.extend_from_slice(b"a");
+            patch.extend(varint(action));
+            patch.extend(varint(offset));
+            with_footer(patch, &source, &target)
+        };
+
+        // TargetCopy repeating the first byte, 3 bytes fits and 4 doesn't
+        assert_eq!(apply(&source, &build((2 << 2) | 3, 0)).unwrap(), target);
+        assert_eq!(
+            apply(&source, &build((usize::MAX >> 3 << 2) | 3, 0)).unwrap_err(),
+            "Patch writes past the end of the target"
+        );
+        assert!(apply(&source, &build((3 << 2) | 3, 0)).is_err());
+
+        // SourceCopy from an offset that overflows
+        assert!(apply(&source, &build((1 << 2) | 2, usize::MAX - 1)).is_err());
+    }
+
+    #[test]
+    fn test_ups_xor_hunks() {
+        let source = vec![1, 2, 3, 4];
+        let target = vec![1, 9, 3, 4, 5];
+
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[2 ^ 9, 0]);
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[5, 0]);
+        let patch = with_footer(patch, &source, &target);
+
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+
+        // A second skip that would wrap around the address space
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[2 ^ 9, 0]);
+        patch.extend(varint(usize::MAX - 1));
+        patch.extend_from_slice(&[5, 0]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply(&source, &patch).unwrap_err(), "Patch is truncated");
+    }
+
+    #[test]
+    fn test_bps_rejects_huge_metadata() {
+        let source = b"abcdef".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(source.len()));
+        patch.extend(varint(usize::MAX));
+        let patch = with_footer(patch, &source, &source);
+        assert_eq!(apply(&source, &patch).unwrap_err(), "Patch is truncated");
+    }
+}
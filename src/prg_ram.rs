@@ -0,0 +1,209 @@
+use crate::cartridge::ROM;
+use crate::savestate::{StateReader, StateWriter};
+
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+const TRAINER_ADDR: u16 = 0x7000;
+
+// Cartridge work RAM at $6000-$7FFF. Mappers decide whether it is enabled and
+// whether writes go through.
+pub struct PrgRam {
+    data: Vec<u8>,
+    // Selected 8K bank, for boards with more RAM than fits in the window
+    bank: usize,
+    enabled: bool,
+    write_protected: bool,
+}
+
+impl PrgRam {
+    pub fn new(size: usize) -> Self {
+        PrgRam {
+            data: vec![0; size],
+            bank: 0,
+            enabled: true,
+            write_protected: false,
+        }
+    }
+
+    // Sized from the header, with the trainer already in place. A trainer
+    // on a board without RAM gets the 8K it needs.
+    pub fn for_rom(rom: &ROM) -> Self {
+        let size = if rom.trainer.is_empty() {
+            rom.work_ram_size()
+        } else {
+            rom.work_ram_size().max(PRG_RAM_BANK_SIZE)
+        };
+
+        let mut prg_ram = PrgRam::new(size);
+        prg_ram.load_trainer(&rom.trainer);
+        prg_ram
+    }
+
+    pub fn load_trainer(&mut self, trainer: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+
+        for (addr, &byte) in (TRAINER_ADDR..).zip(trainer) {
+            let offset = self.offset(addr);
+            self.data[offset] = byte;
+        }
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        (self.bank * PRG_RAM_BANK_SIZE + (addr as usize - 0x6000)) % self.data.len()
+    }
+
+    // None when nothing drives the data bus
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        if !self.enabled || self.data.is_empty() {
+            return None;
+        }
+
+        Some(self.data[self.offset(addr)])
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        if !self.enabled || self.write_protected || self.data.is_empty() {
+            return;
+        }
+
+        let offset = self.offset(addr);
+        self.data[offset] = data;
+    }
+
+    pub fn bank_count(&self) -> usize {
+        (self.data.len() / PRG_RAM_BANK_SIZE).max(1)
+    }
+
+    pub fn set_bank(&mut self, bank: usize) {
+        self.bank = bank % self.bank_count();
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u8(self.bank as u8);
+        state.write_bool(self.enabled);
+        state.write_bool(self.write_protected);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        state.read_bytes_into(&mut self.data)?;
+        self.bank = state.read_u8()? as usize % self.bank_count();
+        self.enabled = state.read_bool()?;
+        self.write_protected = state.read_bool()?;
+        Ok(())
+    }
+
+    // Restores contents from a save file; a short file only fills the start
+    pub fn load(&mut self, data: &[u8]) {
+        let len = data.len().min(self.data.len());
+        self.data[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_mirrors_small_ram_across_window() {
+        let mut prg_ram = PrgRam::new(0x0800);
+        prg_ram.write(0x6001, 0x42);
+        assert_eq!(prg_ram.read(0x6801), Some(0x42));
+        assert_eq!(prg_ram.read(0x7801), Some(0x42));
+    }
+
+    #[test]
+    fn test_no_ram_leaves_bus_open() {
+        let mut prg_ram = PrgRam::new(0);
+        prg_ram.write(0x6000, 0x42);
+        prg_ram.set_bank(3);
+        assert_eq!(prg_ram.read(0x6000), None);
+        assert_eq!(prg_ram.bank_count(), 1);
+    }
+
+    #[test]
+    fn test_disabled_and_write_protected() {
+        let mut prg_ram = PrgRam::new(0x2000);
+        prg_ram.write(0x6000, 1);
+
+        prg_ram.set_write_protected(true);
+        prg_ram.write(0x6000, 2);
+        assert_eq!(prg_ram.read(0x6000), Some(1));
+
+        prg_ram.set_enabled(false);
+        prg_ram.set_write_protected(false);
+        prg_ram.write(0x6000, 3);
+        assert_eq!(prg_ram.read(0x6000), None);
+        prg_ram.set_enabled(true);
+        assert_eq!(prg_ram.read(0x6000), Some(1));
+    }
+
+    #[test]
+    fn test_banks_wrap() {
+        let mut prg_ram = PrgRam::new(0x8000);
+        assert_eq!(prg_ram.bank_count(), 4);
+        prg_ram.set_bank(2);
+        prg_ram.write(0x6000, 0x22);
+        prg_ram.set_bank(6);
+        assert_eq!(prg_ram.read(0x6000), Some(0x22));
+        assert_eq!(prg_ram.data()[0x4000], 0x22);
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        let mut prg_ram = PrgRam::new(0x4000);
+        prg_ram.set_bank(1);
+        prg_ram.write(0x6123, 0x99);
+        prg_ram.set_write_protected(true);
+        let mut state = StateWriter::new();
+        prg_ram.save_state(&mut state);
+        let state = state.finish();
+
+        let mut restored = PrgRam::new(0x4000);
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.read(0x6123), Some(0x99));
+        restored.write(0x6123, 0);
+        assert_eq!(restored.read(0x6123), Some(0x99));
+    }
+
+    #[test]
+    fn test_load_fills_start_of_ram() {
+        let mut prg_ram = PrgRam::new(0x2000);
+        prg_ram.load(&[1, 2, 3]);
+        assert_eq!(&prg_ram.data()[..4], &[1, 2, 3, 0]);
+
+        // A save that is too long is cut off
+        prg_ram.load(&vec![7; 0x3000]);
+        assert_eq!(prg_ram.data().len(), 0x2000);
+        assert_eq!(prg_ram.read(0x7fff), Some(7));
+    }
+
+    #[test]
+    fn test_trainer_lands_at_7000() {
+        let mut rom = test_rom(0, 0, 1, 0x4000, 1, 0x2000);
+        rom.prg_ram_size = 0;
+        rom.trainer = (0..512).map(|i| i as u8 | 1).collect();
+
+        // A board without RAM still gets 8K for the trainer
+        let prg_ram = PrgRam::for_rom(&rom);
+        assert_eq!(prg_ram.data().len(), 0x2000);
+        assert_eq!(prg_ram.read(0x6fff), Some(0));
+        assert_eq!(prg_ram.read(0x7000), Some(1));
+        assert_eq!(prg_ram.read(0x71fe), Some(0xff));
+        assert_eq!(prg_ram.read(0x7200), Some(0));
+    }
+}
@@ -0,0 +1,263 @@
+// Everything known about a loaded ROM, for `kiko-nes info`. The JSON form is
+// for scripts, so its keys and value types should stay stable.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::{ConsoleType, HeaderFormat, ROM};
+use crate::hash::sha1_hex;
+
+pub struct RomInfo<'a> {
+    pub path: &'a Path,
+    pub rom: &'a ROM,
+    // Patches applied before the ROM was parsed, in order
+    pub patches: &'a [PathBuf],
+}
+
+impl RomInfo<'_> {
+    fn format(&self) -> &'static str {
+        match self.rom.header_format {
+            HeaderFormat::INes => "iNES",
+            HeaderFormat::Nes2 => "NES 2.0",
+            HeaderFormat::Unif => "UNIF",
+        }
+    }
+
+    fn console(&self) -> String {
+        match self.rom.console_type {
+            ConsoleType::Nes => "NES".to_string(),
+            ConsoleType::VsSystem { .. } => "Vs. System".to_string(),
+            ConsoleType::Playchoice10 => "PlayChoice-10".to_string(),
+            ConsoleType::Extended(kind) => format!("Extended {}", kind),
+        }
+    }
+
+    // The game database names the exact board. Without an entry this falls
+    // back to the board family the mapper number stands for.
+    fn board(&self) -> Option<String> {
+        let rom = self.rom;
+        if rom.board.is_some() {
+            return rom.board.clone();
+        }
+
+        let family = match (rom.mapper, rom.submapper) {
+            (0, _) => "NROM",
+            (1, _) => "SxROM (MMC1)",
+            (2, _) => "UxROM",
+            (3, _) => "CNROM",
+            (4, 1) => "HKROM (MMC6)",
+            (4, _) => "TxROM (MMC3)",
+            (5, _) => "ExROM (MMC5)",
+            (7, _) => "AxROM",
+            (9, _) => "PxROM (MMC2)",
+            (10, _) => "FxROM (MMC4)",
+            (11, _) => "Color Dreams",
+            (16 | 153 | 157 | 159, _) => "Bandai FCG",
+            (19, _) => "Namco 163",
+            (21 | 22 | 23 | 25, _) => "Konami VRC2/VRC4",
+            (30, _) => "UNROM 512",
+            (34, 1) => "NINA-001",
+            (34, 0) if rom.chr_rom.len() > 0x2000 => "NINA-001",
+            (34, _) => "BNROM",
+            (66, _) => "GxROM",
+            (69, _) => "JLROM (FME-7)",
+            (118, _) => "TxSROM (MMC3)",
+            (119, _) => "TQROM (MMC3)",
+            _ => return None,
+        };
+        Some(family.to_string())
+    }
+
+    pub fn to_text(&self) -> String {
+        let rom = self.rom;
+        let mut out = String::new();
+        let mut line = |label: &str, value: String| {
+            let _ = writeln!(out, "{:<12} {}", format!("{}:", label), value);
+        };
+
+        line("File", self.path.display().to_string());
+        line("Format", self.format().to_string());
+        line("Mapper", format!("{}.{}", rom.mapper, rom.submapper));
+        line("Board", self.board().unwrap_or_else(|| "-".to_string()));
+        line("PRG-ROM", kib(rom.prg_rom.len()));
+        line("CHR-ROM", kib(rom.chr_rom.len()));
+        line(
+            "PRG-RAM",
+            format!(
+                "{} + {} NVRAM",
+                kib(rom.prg_ram_size),
+                kib(rom.prg_nvram_size)
+            ),
+        );
+        line(
+            "CHR-RAM",
+            format!(
+                "{} + {} NVRAM",
+                kib(rom.chr_ram_size),
+                kib(rom.chr_nvram_size)
+            ),
+        );
+        line("Mirroring", format!("{:?}", rom.mirroring));
+        line("Battery", yes_no(rom.battery));
+        line("Trainer", yes_no(!rom.trainer.is_empty()));
+        line("Region", format!("{:?}", rom.timing));
+        line("Console", self.console());
+        line("CRC32", format!("{:08x}", rom.crc32));
+        line("SHA-1", sha1_hex(&rom.sha1));
+        for patch in self.patches {
+            line("Patch", patch.display().to_string());
+        }
+        for correction in &rom.corrections {
+            line("Corrected", correction.to_string());
+        }
+        for warning in &rom.warnings {
+            line("Warning", warning.to_string());
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let rom = self.rom;
+        let strings = |values: Vec<String>| -> String {
+            let values: Vec<String> = values.iter().map(|value| json_string(value)).collect();
+            format!("[{}]", values.join(", "))
+        };
+
+        let fields = [
+            ("file", json_string(&self.path.display().to_string())),
+            ("format", json_string(self.format())),
+            ("mapper", rom.mapper.to_string()),
+            ("submapper", rom.submapper.to_string()),
+            (
+                "board",
+                self.board()
+                    .as_deref()
+                    .map_or("null".to_string(), json_string),
+            ),
+            ("prg_rom_size", rom.prg_rom.len().to_string()),
+            ("chr_rom_size", rom.chr_rom.len().to_string()),
+            ("prg_ram_size", rom.prg_ram_size.to_string()),
+            ("prg_nvram_size", rom.prg_nvram_size.to_string()),
+            ("chr_ram_size", rom.chr_ram_size.to_string()),
+            ("chr_nvram_size", rom.chr_nvram_size.to_string()),
+            ("mirroring", json_string(&format!("{:?}", rom.mirroring))),
+            ("battery", rom.battery.to_string()),
+            ("trainer", (!rom.trainer.is_empty()).to_string()),
+            ("region", json_string(&format!("{:?}", rom.timing))),
+            ("console", json_string(&self.console())),
+            ("crc32", json_string(&format!("{:08x}", rom.crc32))),
+            ("sha1", json_string(&sha1_hex(&rom.sha1))),
+            (
+                "patches",
+                strings(
+                    self.patches
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect(),
+                ),
+            ),
+            (
+                "corrections",
+                strings(rom.corrections.iter().map(|c| c.to_string()).collect()),
+            ),
+            (
+                "warnings",
+                strings(rom.warnings.iter().map(|w| w.to_string()).collect()),
+            ),
+        ];
+
+        let mut out = "{\n".to_string();
+        for (i, (key, value)) in fields.iter().enumerate() {
+            let comma = if i + 1 < fields.len() { "," } else { "" };
+            let _ = writeln!(out, "  {}: {}{}", json_string(key), value, comma);
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn kib(size: usize) -> String {
+    if size & 0x3ff == 0 {
+        format!("{}K", size / 1024)
+    } else {
+        format!("{} bytes", size)
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test_rom;
+
+    #[test]
+    fn test_json_lists_header_values() {
+        let rom = test_rom(4, 1, 2, 0x4000, 1, 0x2000);
+        let patches = vec![PathBuf::from("fix \"1\".ips")];
+        let info = RomInfo {
+            path: Path::new("game.nes"),
+            rom: &rom,
+            patches: &patches,
+        };
+
+        let json = info.to_json();
+        assert!(json.starts_with("{\n  \"file\": \"game.nes\",\n"));
+        assert!(json.contains("  \"format\": \"NES 2.0\",\n"));
+        assert!(json.contains("  \"mapper\": 4,\n  \"submapper\": 1,\n"));
+        assert!(json.contains("  \"board\": \"HKROM (MMC6)\",\n"));
+        assert!(json.contains("  \"prg_rom_size\": 32768,\n"));
+        assert!(json.contains("  \"battery\": false,\n"));
+        assert!(json.contains(&format!("  \"crc32\": \"{:08x}\",\n", rom.crc32)));
+        assert!(json.contains("  \"patches\": [\"fix \\\"1\\\".ips\"],\n"));
+        assert!(json.ends_with("  \"warnings\": []\n}\n"));
+
+        let text = info.to_text();
+        assert!(text.contains("Mapper:      4.1\n"));
+        assert!(text.contains("PRG-ROM:     32K\n"));
+    }
+
+    #[test]
+    fn test_board_falls_back_to_mapper_family() {
+        let board = |mut rom: ROM, db_board: Option<&str>| {
+            rom.board = db_board.map(str::to_string);
+            let info = RomInfo {
+                path: Path::new("game.nes"),
+                rom: &rom,
+                patches: &[],
+            };
+            info.board()
+        };
+
+        let rom = || test_rom(1, 0, 2, 0x4000, 1, 0x2000);
+        assert_eq!(board(rom(), None).as_deref(), Some("SxROM (MMC1)"));
+        assert_eq!(board(rom(), Some("SNROM")).as_deref(), Some("SNROM"));
+        assert_eq!(board(test_rom(255, 0, 2, 0x4000, 1, 0x2000), None), None);
+    }
+
+    #[test]
+    fn test_json_string_escapes_control_characters() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
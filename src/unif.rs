@@ -0,0 +1,313 @@
+// UNIF dumps: a 32 byte header followed by tagged chunks. The board is named
+// rather than numbered, so it has to be translated to one of our mappers.
+
+use crate::cartridge::{rom_hashes, ConsoleType, HeaderFormat, Mirroring, Timing, ROM};
+
+const UNIF_TAG: &[u8] = b"UNIF";
+const HEADER_SIZE: usize = 32;
+const CHUNK_HEADER_SIZE: usize = 8;
+const PRG_RAM_SIZE: usize = 0x2000;
+const CHR_RAM_SIZE: usize = 0x2000;
+const MAX_ROM_SIZE: usize = 0x400_0000;
+
+// Prefixes naming who made the board, which don't change how it works
+const BOARD_PREFIXES: [&str; 5] = ["NES-", "HVC-", "UNL-", "BMC-", "BTL-"];
+
+// Board name to (mapper, submapper)
+const BOARDS: &[(&str, u16, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("RROM", 0, 0),
+    ("RROM-128", 0, 0),
+    ("SAROM", 1, 0),
+    ("SBROM", 1, 0),
+    ("SCROM", 1, 0),
+    ("SEROM", 1, 0),
+    ("SFROM", 1, 0),
+    ("SGROM", 1, 0),
+    ("SHROM", 1, 0),
+    ("SJROM", 1, 0),
+    ("SKROM", 1, 0),
+    ("SLROM", 1, 0),
+    ("SL1ROM", 1, 0),
+    ("SNROM", 1, 0),
+    ("SOROM", 1, 0),
+    ("SUROM", 1, 0),
+    ("SXROM", 1, 0),
+    ("UNROM", 2, 0),
+    ("UOROM", 2, 0),
+    ("CNROM", 3, 0),
+    ("TBROM", 4, 0),
+    ("TEROM", 4, 0),
+    ("TFROM", 4, 0),
+    ("TGROM", 4, 0),
+    ("TKROM", 4, 0),
+    ("TLROM", 4, 0),
+    ("TL1ROM", 4, 0),
+    ("TNROM", 4, 0),
+    ("TR1ROM", 4, 0),
+    ("TSROM", 4, 0),
+    ("TVROM", 4, 0),
+    ("HKROM", 4, 1),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("AMROM", 7, 0),
+    ("ANROM", 7, 0),
+    ("AN1ROM", 7, 0),
+    ("AOROM", 7, 0),
+    ("PNROM", 9, 0),
+    ("PEEOROM", 9, 0),
+    ("FJROM", 10, 0),
+    ("FKROM", 10, 0),
+    ("UNROM-512-8", 30, 0),
+    ("UNROM-512-16", 30, 0),
+    ("UNROM-512-32", 30, 0),
+    ("BNROM", 34, 2),
+    ("GNROM", 66, 0),
+    ("MHROM", 66, 0),
+    ("JLROM", 69, 0),
+    ("JSROM", 69, 0),
+    ("TKSROM", 118, 0),
+    ("TLSROM", 118, 0),
+    ("TQROM", 119, 0),
+];
+
+pub fn is_unif(raw: &[u8]) -> bool {
+    raw.starts_with(UNIF_TAG)
+}
+
+// Mapper and submapper for a MAPR board name
+pub fn board_mapper(name: &str) -> Option<(u16, u8)> {
+    let name = BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+
+    BOARDS
+        .iter()
+        .find(|(board, _, _)| board.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper, submapper)| (mapper, submapper))
+}
+
+pub fn parse(raw: &[u8]) -> Result<ROM, String> {
+    if raw.len() < HEADER_SIZE || !is_unif(raw) {
+        return Err("File is not in UNIF file format".to_string());
+    }
+
+    let mut board = None;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirroring = Mirroring::Horizontal;
+    let mut battery = false;
+    let mut timing = Timing::Ntsc;
+    let mut expansion_device = 0;
+
+    let mut data = &raw[HEADER_SIZE..];
+    while !data.is_empty() {
+        if data.len() < CHUNK_HEADER_SIZE {
+            return Err("UNIF chunk header is truncated".to_string());
+        }
+        let id = &data[..4];
+        let len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        data = &data[CHUNK_HEADER_SIZE..];
+        if len > data.len() {
+            return Err(format!(
+                "UNIF chunk {} is truncated: expected {} bytes, found {}",
+                String::from_utf8_lossy(id),
+                len,
+                data.len()
+            ));
+        }
+        let chunk = &data[..len];
+        data = &data[len..];
+
+        match id {
+            b"MAPR" => {
+                // Null terminated, but not always
+                let end = chunk.iter().position(|&b| b == 0).unwrap_or(chunk.len());
+                board = Some(String::from_utf8_lossy(&chunk[..end]).trim().to_string());
+            }
+            b"MIRR" => {
+                mirroring = match chunk.first() {
+                    Some(1) => Mirroring::Vertical,
+                    Some(2) => Mirroring::SingleScreenLower,
+                    Some(3) => Mirroring::SingleScreenUpper,
+                    Some(4) => Mirroring::FourScreen,
+                    // 5 means the mapper controls it
+                    _ => Mirroring::Horizontal,
+                }
+            }
+            b"BATR" => battery = true,
+            b"TVCI" => {
+                timing = match chunk.first() {
+                    Some(1) => Timing::Pal,
+                    Some(2) => Timing::MultiRegion,
+                    _ => Timing::Ntsc,
+                }
+            }
+            b"CTRL" => expansion_device = ctrl_expansion_device(chunk.first().copied()),
+            _ => {
+                if let Some(index) = rom_chunk_index(id, b"PRG") {
+                    prg_chunks[index] = Some(chunk);
+                } else if let Some(index) = rom_chunk_index(id, b"CHR") {
+                    chr_chunks[index] = Some(chunk);
+                }
+                // Anything else is a name, credits or dump info
+            }
+        }
+    }
+
+    let board = board.ok_or_else(|| "UNIF file has no MAPR chunk".to_string())?;
+    let (mapper, submapper) =
+        board_mapper(&board).ok_or_else(|| format!("Unsupported UNIF board {}", board))?;
+
+    let prg_rom: Vec<u8> = prg_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    let chr_rom: Vec<u8> = chr_chunks
+        .iter()
+        .flatten()
+        .flat_map(|c| c.iter())
+        .copied()
+        .collect();
+    if prg_rom.is_empty() {
+        return Err("UNIF file has no PRG chunks".to_string());
+    }
+    if prg_rom.len() > MAX_ROM_SIZE || chr_rom.len() > MAX_ROM_SIZE {
+        return Err("UNIF file has an impossibly large ROM".to_string());
+    }
+
+    // UNIF has no RAM sizes, so assume what the iNES loader does
+    let (prg_ram_size, prg_nvram_size) = if battery {
+        (0, PRG_RAM_SIZE)
+    } else {
+        (PRG_RAM_SIZE, 0)
+    };
+    let chr_ram_size = if chr_rom.is_empty() { CHR_RAM_SIZE } else { 0 };
+
+    let (crc32, sha1) = rom_hashes(&prg_rom, &chr_rom);
+
+    Ok(ROM {
+        crc32,
+        sha1,
+        board: Some(board),
+        corrections: vec![],
+        prg_rom,
+        chr_rom,
+        header_format: HeaderFormat::Unif,
+        mapper,
+        submapper,
+        mirroring,
+        battery,
+        prg_ram_size,
+        prg_nvram_size,
+        chr_ram_size,
+        chr_nvram_size: 0,
+        timing,
+        console_type: ConsoleType::Nes,
+        misc_rom_count: 0,
+        expansion_device,
+        inst_rom: vec![],
+        playchoice_prom: vec![],
+        misc_rom: vec![],
+        trainer: vec![],
+        warnings: vec![],
+    })
+}
+
+// PRG0-PRGF and CHR0-CHRF, numbered with a hex digit
+fn rom_chunk_index(id: &[u8], kind: &[u8]) -> Option<usize> {
+    if &id[..3] != kind {
+        return None;
+    }
+    (id[3] as char).to_digit(16).map(|digit| digit as usize)
+}
+
+// CTRL is a bitmask of supported controllers. Pick the first special one as
+// the NES 2.0 expansion device, falling back to standard joypads.
+fn ctrl_expansion_device(ctrl: Option<u8>) -> u8 {
+    let ctrl = match ctrl {
+        Some(ctrl) => ctrl,
+        None => return 0,
+    };
+
+    if ctrl & 0b10 != 0 {
+        0x08 // Zapper
+    } else if ctrl & 0b1000 != 0 {
+        0x0f // Arkanoid controller
+    } else if ctrl & 0b1_0000 != 0 {
+        0x0b // Power Pad
+    } else if ctrl & 0b10_0000 != 0 {
+        0x02 // Four Score
+    } else {
+        0x01
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut raw = b"UNIF".to_vec();
+        raw.extend_from_slice(&7u32.to_le_bytes());
+        raw.resize(HEADER_SIZE, 0);
+        for c in chunks {
+            raw.extend_from_slice(c);
+        }
+        raw
+    }
+
+    #[test]
+    fn test_parses_chunks_into_rom() {
+        let raw = unif(&[
+            chunk(b"MAPR", b"NES-SNROM\0"),
+            // Out of order on purpose
+            chunk(b"PRG1", &[2; 0x4000]),
+            chunk(b"PRG0", &[1; 0x4000]),
+            chunk(b"MIRR", &[1]),
+            chunk(b"BATR", &[1]),
+            chunk(b"NAME", b"Test\0"),
+        ]);
+
+        let rom = ROM::with_game_db(&raw, false).unwrap();
+        assert_eq!(rom.header_format, HeaderFormat::Unif);
+        assert_eq!((rom.mapper, rom.submapper), (1, 0));
+        assert_eq!(rom.board.as_deref(), Some("NES-SNROM"));
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!(rom.prg_rom[0], 1);
+        assert_eq!(rom.prg_rom[0x4000], 2);
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.chr_ram_size, 0x2000);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.prg_nvram_size, 0x2000);
+        assert_eq!((rom.crc32, rom.sha1), rom_hashes(&rom.prg_rom, &[]));
+    }
+
+    #[test]
+    fn test_rejects_unknown_board_and_truncated_chunk() {
+        let raw = unif(&[chunk(b"MAPR", b"UNL-MYSTERY\0"), chunk(b"PRG0", &[0; 16])]);
+        assert_eq!(
+            ROM::new(&raw).err().unwrap(),
+            "Unsupported UNIF board UNL-MYSTERY"
+        );
+
+        let mut raw = unif(&[chunk(b"MAPR", b"NROM\0"), chunk(b"PRG0", &[0; 16])]);
+        raw.truncate(raw.len() - 1);
+        assert!(ROM::new(&raw).is_err());
+    }
+}
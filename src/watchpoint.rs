@@ -0,0 +1,349 @@
+use std::ops::RangeInclusive;
+
+bitflags! {
+    pub struct WatchKind: u8 {
+        const READ    = 0b0000_0001;
+        const WRITE   = 0b0000_0010;
+        const EXECUTE = 0b0000_0100;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressSpace {
+    Cpu,
+    Ppu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub id: WatchId,
+    pub space: AddressSpace,
+    pub kind: WatchKind,
+    // The address as issued by the CPU/PPU, before mirroring
+    pub addr: u16,
+    pub pc: u16,
+    pub value: u8,
+    // Only known for writes to memory that can be read without side effects
+    pub old_value: Option<u8>,
+}
+
+pub enum WatchAction {
+    Break,
+    Callback(Box<dyn FnMut(&WatchHit)>),
+}
+
+struct Watchpoint {
+    id: WatchId,
+    space: AddressSpace,
+    kind: WatchKind,
+    action: WatchAction,
+    // One bit per canonical address, so a range given in any mirror matches
+    // accesses made through every other mirror of it
+    mask: Vec<u64>,
+}
+
+impl Watchpoint {
+    fn matches(&self, space: AddressSpace, kind: WatchKind, canonical: u16) -> bool {
+        let idx = canonical as usize;
+        self.space == space
+            && self.kind.intersects(kind)
+            && self.mask[idx / 64] & (1 << (idx % 64)) != 0
+    }
+}
+
+pub struct Watchpoints {
+    entries: Vec<Watchpoint>,
+    next_id: usize,
+    pc: u16,
+    pending_break: Option<WatchHit>,
+    // Set when an execute watchpoint breaks, so resuming doesn't hit it again
+    resume_pc: Option<u16>,
+}
+
+// $0800-$1FFF mirrors RAM, $2008-$3FFF mirrors the PPU registers
+pub fn canonical_cpu_addr(addr: u16) -> u16 {
+    match addr {
+        0x0000..=0x1fff => addr & 0b0000_0111_1111_1111,
+        0x2000..=0x3fff => addr & 0b0010_0000_0000_0111,
+        _ => addr,
+    }
+}
+
+// $3000-$3EFF mirrors the nametables, $3F20-$3FFF the palette, and
+// $3F10/$3F14/$3F18/$3F1C the background entries of the palette
+pub fn canonical_ppu_addr(addr: u16) -> u16 {
+    let addr = addr & 0x3fff;
+    match addr {
+        0x3000..=0x3eff => addr - 0x1000,
+        0x3f00..=0x3fff => {
+            let palette = addr & 0x1f;
+            if palette & 0x13 == 0x10 {
+                0x3f00 | (palette & 0x0f)
+            } else {
+                0x3f00 | palette
+            }
+        }
+        _ => addr,
+    }
+}
+
+fn canonical_addr(space: AddressSpace, addr: u16) -> u16 {
+    match space {
+        AddressSpace::Cpu => canonical_cpu_addr(addr),
+        AddressSpace::Ppu => canonical_ppu_addr(addr),
+    }
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints {
+            entries: vec![],
+            next_id: 0,
+            pc: 0,
+            pending_break: None,
+            resume_pc: None,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        space: AddressSpace,
+        range: RangeInclusive<u16>,
+        kind: WatchKind,
+        action: WatchAction,
+    ) -> WatchId {
+        let mut mask = vec![0u64; 0x10000 / 64];
+        for addr in range {
+            let idx = canonical_addr(space, addr) as usize;
+            mask[idx / 64] |= 1 << (idx % 64);
+        }
+
+        let id = WatchId(self.next_id);
+        self.next_id += 1;
+        self.entries.push(Watchpoint {
+            id,
+            space,
+            kind,
+            action,
+            mask,
+        });
+
+        id
+    }
+
+    pub fn remove(&mut self, id: WatchId) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|w| w.id != id);
+        self.entries.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.pending_break = None;
+        self.resume_pc = None;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pending_break.is_some()
+    }
+
+    // Returns the access that paused emulation and lets it continue
+    pub fn take_break(&mut self) -> Option<WatchHit> {
+        self.pending_break.take()
+    }
+
+    pub fn watches(&self, space: AddressSpace, kind: WatchKind) -> bool {
+        self.entries
+            .iter()
+            .any(|w| w.space == space && w.kind.intersects(kind))
+    }
+
+    pub(crate) fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub(crate) fn check(
+        &mut self,
+        space: AddressSpace,
+        kind: WatchKind,
+        addr: u16,
+        value: u8,
+        old_value: Option<u8>,
+    ) {
+        if kind == WatchKind::EXECUTE && self.resume_pc.take() == Some(addr) {
+            return;
+        }
+
+        let canonical = canonical_addr(space, addr);
+        let pc = self.pc;
+
+        for watchpoint in self.entries.iter_mut() {
+            if !watchpoint.matches(space, kind, canonical) {
+                continue;
+            }
+
+            let hit = WatchHit {
+                id: watchpoint.id,
+                space,
+                kind,
+                addr,
+                pc,
+                value,
+                old_value,
+            };
+
+            match watchpoint.action {
+                WatchAction::Callback(ref mut callback) => callback(&hit),
+                WatchAction::Break => {
+                    if self.pending_break.is_none() {
+                        if kind == WatchKind::EXECUTE {
+                            self.resume_pc = Some(addr);
+                        }
+                        self.pending_break = Some(hit);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Watchpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::{Mem, CPU};
+    use crate::mapper::{from_rom, test_rom};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn recorder() -> (Rc<RefCell<Vec<WatchHit>>>, WatchAction) {
+        let hits = Rc::new(RefCell::new(vec![]));
+        let recorded = hits.clone();
+        let action = WatchAction::Callback(Box::new(move |hit: &WatchHit| {
+            recorded.borrow_mut().push(*hit)
+        }));
+        (hits, action)
+    }
+
+    #[test]
+    fn test_cpu_ranges_match_every_mirror() {
+        let mut watchpoints = Watchpoints::new();
+        let (hits, action) = recorder();
+        watchpoints.add(AddressSpace::Cpu, 0x0810..=0x0811, WatchKind::WRITE, action);
+
+        watchpoints.check(AddressSpace::Cpu, WatchKind::WRITE, 0x0010, 1, None);
+        watchpoints.check(AddressSpace::Cpu, WatchKind::WRITE, 0x1811, 2, None);
+        watchpoints.check(AddressSpace::Cpu, WatchKind::WRITE, 0x0012, 3, None);
+        watchpoints.check(AddressSpace::Cpu, WatchKind::READ, 0x0010, 4, None);
+        watchpoints.check(AddressSpace::Ppu, WatchKind::WRITE, 0x0010, 5, None);
+
+        let addrs: Vec<_> = hits.borrow().iter().map(|hit| hit.addr).collect();
+        assert_eq!(addrs, [0x0010, 0x1811]);
+    }
+
+    #[test]
+    fn test_ppu_ranges_match_nametable_and_palette_mirrors() {
+        assert_eq!(canonical_ppu_addr(0x3400), 0x2400);
+        assert_eq!(canonical_ppu_addr(0x3f31), 0x3f11);
+        assert_eq!(canonical_ppu_addr(0x3f10), 0x3f00);
+        assert_eq!(canonical_ppu_addr(0x3f11), 0x3f11);
+        assert_eq!(canonical_cpu_addr(0x3456), 0x2006);
+
+        let mut watchpoints = Watchpoints::new();
+        let (hits, action) = recorder();
+        watchpoints.add(AddressSpace::Ppu, 0x3f10..=0x3f10, WatchKind::READ, action);
+        watchpoints.check(AddressSpace::Ppu, WatchKind::READ, 0x3f00, 0, None);
+        watchpoints.check(AddressSpace::Ppu, WatchKind::READ, 0x7f20, 0, None);
+        watchpoints.check(AddressSpace::Ppu, WatchKind::READ, 0x3f01, 0, None);
+        assert_eq!(hits.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_break_pauses_until_taken() {
+        let mut watchpoints = Watchpoints::new();
+        let id = watchpoints.add(
+            AddressSpace::Cpu,
+            0x6000..=0x6000,
+            WatchKind::READ | WatchKind::WRITE,
+            WatchAction::Break,
+        );
+        assert!(watchpoints.watches(AddressSpace::Cpu, WatchKind::WRITE));
+        assert!(!watchpoints.watches(AddressSpace::Cpu, WatchKind::EXECUTE));
+
+        watchpoints.set_pc(0x8123);
+        watchpoints.check(AddressSpace::Cpu, WatchKind::WRITE, 0x6000, 7, Some(6));
+        // Only the first hit is kept
+        watchpoints.check(AddressSpace::Cpu, WatchKind::READ, 0x6000, 7, None);
+        assert!(watchpoints.is_paused());
+
+        let hit = watchpoints.take_break().unwrap();
+        assert_eq!(hit.id, id);
+        assert_eq!(hit.kind, WatchKind::WRITE);
+        assert_eq!(hit.pc, 0x8123);
+        assert_eq!((hit.value, hit.old_value), (7, Some(6)));
+        assert!(!watchpoints.is_paused());
+
+        assert!(watchpoints.remove(id));
+        assert!(!watchpoints.remove(id));
+        assert!(watchpoints.is_empty());
+    }
+
+    #[test]
+    fn test_execute_break_is_skipped_once_on_resume() {
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.add(
+            AddressSpace::Cpu,
+            0x8000..=0x8000,
+            WatchKind::EXECUTE,
+            WatchAction::Break,
+        );
+
+        watchpoints.check(AddressSpace::Cpu, WatchKind::EXECUTE, 0x8000, 0xea, None);
+        assert!(watchpoints.take_break().is_some());
+        watchpoints.check(AddressSpace::Cpu, WatchKind::EXECUTE, 0x8000, 0xea, None);
+        assert!(!watchpoints.is_paused());
+        watchpoints.check(AddressSpace::Cpu, WatchKind::EXECUTE, 0x8000, 0xea, None);
+        assert!(watchpoints.is_paused());
+    }
+
+    #[test]
+    fn test_read_modify_write_hits_write_watchpoint_twice() {
+        let rom = test_rom(0, 0, 1, 0x4000, 1, 0x2000);
+        let mut cpu = CPU::new(Bus::new(from_rom(rom).unwrap()));
+        // INC $10, then BRK
+        for (i, byte) in [0xe6, 0x10, 0x00].iter().enumerate() {
+            cpu.mem_write(0x0600 + i as u16, *byte);
+        }
+        cpu.mem_write(0x0010, 0x41);
+
+        let (hits, action) = recorder();
+        cpu.bus
+            .watchpoints
+            .add(AddressSpace::Cpu, 0x0010..=0x0010, WatchKind::WRITE, action);
+        cpu.program_counter = 0x0600;
+        cpu.run();
+
+        let writes: Vec<_> = hits
+            .borrow()
+            .iter()
+            .map(|hit| (hit.pc, hit.value, hit.old_value))
+            .collect();
+        assert_eq!(
+            writes,
+            [(0x0600, 0x41, Some(0x41)), (0x0600, 0x42, Some(0x41))]
+        );
+    }
+}